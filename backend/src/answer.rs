@@ -0,0 +1,235 @@
+//! Typed answers and automatic grading.
+//!
+//! A card can specify one or more expected answers. When reviewing such a card, the user types in
+//! their answer, which is compared to the expected answers in order to suggest a score.
+
+use std::cmp;
+
+use crate::cards::Score;
+
+/// An accepted answer to a card.
+#[derive(Debug)]
+pub enum Answer {
+    /// A literal answer.
+    ///
+    /// This is compared case- and whitespace-insensitively.
+    Text(String),
+    /// A regular expression the whole answer must match.
+    ///
+    /// The regular expression is case-insensitive and matched against the normalized answer.
+    Regex(regex::Regex),
+}
+
+impl Answer {
+    /// Create a literal answer.
+    pub fn text(s: &str) -> Answer {
+        Answer::Text(s.to_string())
+    }
+
+    /// Create an answer from a regular expression.
+    pub fn regex(s: &str) -> Result<Answer, regex::Error> {
+        regex::RegexBuilder::new(&format!("^(?:{})$", s))
+            .case_insensitive(true)
+            .build()
+            .map(Answer::Regex)
+    }
+
+    /// Get a human-readable representation of the answer.
+    pub fn as_str(&self) -> &str {
+        match self {
+            Answer::Text(ref s) => s,
+            Answer::Regex(ref re) => re.as_str(),
+        }
+    }
+
+    /// Get the answer as it is written in the deck.
+    ///
+    /// Unlike `as_str`, this leaves out the anchors added to regular expressions.
+    pub(crate) fn source(&self) -> &str {
+        match self {
+            Answer::Text(ref s) => s,
+            Answer::Regex(ref re) => re.as_str()
+                .strip_prefix("^(?:")
+                .and_then(|s| s.strip_suffix(")$"))
+                .unwrap_or_else(|| re.as_str()),
+        }
+    }
+}
+
+/// A chunk of a character diff between the given answer and the expected answer.
+#[derive(Debug, PartialEq)]
+pub enum DiffChunk {
+    /// Text present in both answers.
+    Same(String),
+    /// Text that is in the expected answer but missing from the given answer.
+    Missing(String),
+    /// Text that is in the given answer but not in the expected answer.
+    Extra(String),
+}
+
+/// The result of grading a typed answer.
+#[derive(Debug)]
+pub struct Grade {
+    /// The suggested score of the review.
+    pub score: Score,
+    /// The closest expected answer, if the answer did not match exactly.
+    pub expected: Option<String>,
+    /// A character diff between the given answer and `expected`.
+    ///
+    /// This is empty if the answer matched or if there is no literal answer to compare against.
+    pub diff: Vec<DiffChunk>,
+}
+
+/// Canonicalize an answer for comparison.
+///
+/// This lowercases the answer, trims it and collapses any whitespace into single spaces.
+fn normalize(s: &str) -> String {
+    s.split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Calculate the Levenshtein distance between two strings (in characters).
+pub(crate) fn edit_distance(a: &[char], b: &[char]) -> usize {
+    // The previous row of the dynamic programming table.
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut row = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            row[j + 1] = cmp::min(
+                cmp::min(row[j] + 1, prev[j + 1] + 1),
+                prev[j] + if ca == cb { 0 } else { 1 },
+            );
+        }
+        prev = row;
+    }
+
+    prev[b.len()]
+}
+
+/// Compute a character diff from `given` to `expected` based on the longest common subsequence.
+fn diff(given: &[char], expected: &[char]) -> Vec<DiffChunk> {
+    // `lcs[i][j]` is the length of the longest common subsequence of `given[i..]` and
+    // `expected[j..]`.
+    let mut lcs = vec![vec![0; expected.len() + 1]; given.len() + 1];
+    for i in (0..given.len()).rev() {
+        for j in (0..expected.len()).rev() {
+            lcs[i][j] = if given[i] == expected[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+
+    // Walk the table and merge consecutive characters of the same kind into chunks.
+    let mut chunks = Vec::new();
+    let mut push = |chunk: fn(String) -> DiffChunk, c: char| {
+        match (chunks.last_mut(), chunk(String::new())) {
+            (Some(DiffChunk::Same(ref mut s)), DiffChunk::Same(_))
+                | (Some(DiffChunk::Missing(ref mut s)), DiffChunk::Missing(_))
+                | (Some(DiffChunk::Extra(ref mut s)), DiffChunk::Extra(_)) => s.push(c),
+            _ => chunks.push(chunk(c.to_string())),
+        }
+    };
+    let (mut i, mut j) = (0, 0);
+    while i < given.len() || j < expected.len() {
+        if i < given.len() && j < expected.len() && given[i] == expected[j] {
+            push(DiffChunk::Same, given[i]);
+            i += 1;
+            j += 1;
+        } else if j < expected.len() && (i == given.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            push(DiffChunk::Missing, expected[j]);
+            j += 1;
+        } else {
+            push(DiffChunk::Extra, given[i]);
+            i += 1;
+        }
+    }
+
+    chunks
+}
+
+/// Grade the typed answer `given` against the accepted answers `answers`.
+///
+/// An answer matching any of the accepted answers is suggested `Good`. Otherwise, it is compared
+/// to the closest literal answer: a small typo (at most one wrong character per ten characters)
+/// is suggested `Hard`, and anything else `Fail`.
+pub fn grade(answers: &[Answer], given: &str) -> Grade {
+    let given = normalize(given);
+
+    // Check if any of the answers match.
+    if answers.iter().any(|answer| match answer {
+        Answer::Text(ref s) => normalize(s) == given,
+        Answer::Regex(ref re) => re.is_match(&given),
+    }) {
+        return Grade {
+            score: Score::Good,
+            expected: None,
+            diff: Vec::new(),
+        };
+    }
+
+    // Find the closest literal answer.
+    let given_chars: Vec<char> = given.chars().collect();
+    let closest = answers.iter()
+        .filter_map(|answer| match answer {
+            Answer::Text(ref s) => Some(normalize(s)),
+            Answer::Regex(_) => None,
+        })
+        .map(|expected| {
+            let expected_chars: Vec<char> = expected.chars().collect();
+            (edit_distance(&given_chars, &expected_chars), expected_chars)
+        })
+        .min_by_key(|&(dist, _)| dist);
+
+    match closest {
+        Some((dist, expected)) => Grade {
+            score: if dist <= cmp::max(1, expected.len() / 10) {
+                Score::Hard
+            } else {
+                Score::Fail
+            },
+            diff: diff(&given_chars, &expected),
+            expected: Some(expected.into_iter().collect()),
+        },
+        // Only regular expressions were given, so there is nothing to compare against.
+        None => Grade {
+            score: Score::Fail,
+            expected: answers.first().map(|answer| answer.as_str().to_string()),
+            diff: Vec::new(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_match() {
+        let answers = [Answer::text("Yoneda lemma"), Answer::regex(r"yoneda('s)?").unwrap()];
+        assert_eq!(grade(&answers, "  yoneda   LEMMA ").score, Score::Good);
+        assert_eq!(grade(&answers, "Yoneda's").score, Score::Good);
+    }
+
+    #[test]
+    fn typo() {
+        let answers = [Answer::text("Grassmannian")];
+        let grade = grade(&answers, "grasmannian");
+        assert_eq!(grade.score, Score::Hard);
+        assert_eq!(grade.expected.as_ref().unwrap(), "grassmannian");
+        assert_eq!(grade.diff, vec![
+            DiffChunk::Same("gras".to_string()),
+            DiffChunk::Missing("s".to_string()),
+            DiffChunk::Same("mannian".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn wrong() {
+        let answers = [Answer::text("fibration"), Answer::text("cofibration")];
+        assert_eq!(grade(&answers, "spectral sequence").score, Score::Fail);
+    }
+}
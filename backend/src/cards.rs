@@ -1,11 +1,16 @@
 //! Content and state of flashcards.
 
-use std::{env, process, io, cmp, fmt};
+use std::collections::BTreeMap;
+use std::{env, process, io, iter, cmp, fmt, fs, path, str, thread, time};
+use std::io::BufRead;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::os::unix::process::CommandExt;
+use std::sync::mpsc;
 
 use chrono;
 use serde::{Serialize, Deserialize};
 
-use crate::{now, settings, Time};
+use crate::{answer, now, settings, Time};
 
 // TODO: Remove all comments in this file and write new comments to explain the details of the
 //       formulas.
@@ -35,6 +40,28 @@ pub type CardId = String;
 /// A card's priority; takes a value 0-4.
 pub type Priority = u8;
 
+/// The separator of the components of hierarchical tags, such as `Math::Algebra::Groups`.
+pub const TAG_SEPARATOR: &str = "::";
+
+/// Get the ancestors of `tag`, starting with `tag` itself and ending with its top-level tag.
+///
+/// For example, the ancestors of `Math::Algebra::Groups` are `Math::Algebra::Groups`,
+/// `Math::Algebra` and `Math`.
+pub fn tag_ancestors(tag: &str) -> impl Iterator<Item = &str> {
+    let mut next = Some(tag);
+    iter::from_fn(move || {
+        let tag = next?;
+        // Cut off the last component.
+        next = tag.rfind(TAG_SEPARATOR).map(|end| &tag[..end]);
+        Some(tag)
+    })
+}
+
+/// Check if `tag` matches the filter `filter`, i.e. if it is `filter` or one of its descendants.
+pub fn tag_matches(tag: &str, filter: &str) -> bool {
+    tag_ancestors(tag).any(|ancestor| ancestor == filter)
+}
+
 /// The number of priority levels.
 pub const PRIORITIES: usize = 5;
 /// The number of scores.
@@ -67,8 +94,26 @@ impl fmt::Display for Score {
     }
 }
 
+impl str::FromStr for Score {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Score, ()> {
+        match s {
+            "fail" => Ok(Score::Fail),
+            "hard" => Ok(Score::Hard),
+            "okay" => Ok(Score::Okay),
+            "good" => Ok(Score::Good),
+            "easy" => Ok(Score::Easy),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The scores ordered after their number.
+const SCORES_IN_ORDER: [Score; SCORES] = [Score::Fail, Score::Hard, Score::Okay, Score::Good, Score::Easy];
+
 /// A card's state.
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
 pub enum CardState {
     /// A new, unreviewed card.
     New,
@@ -80,6 +125,17 @@ pub enum CardState {
     Learnt,
 }
 
+impl fmt::Display for CardState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CardState::New => write!(f, "new"),
+            CardState::Learning(_) => write!(f, "learning"),
+            CardState::Relearning(_) => write!(f, "relearning"),
+            CardState::Learnt => write!(f, "learnt"),
+        }
+    }
+}
+
 /// A card review.
 ///
 /// This is used to track the history of a card.
@@ -119,24 +175,289 @@ impl View {
 }
 
 /// A `sh` (hopefully POSIX) command.
+///
+/// Commands can be used for interactive cards. Such a command can report the result of the review
+/// back to mu in two ways:
+///
+/// 1. By writing lines to the file named by `$MU_RESULT_FILE`. The line `MU_SCORE=<score>`
+///    suggests a score (which the user may confirm or override), whereas `MU_REVIEW=<score>`
+///    reviews the card with the score right away. `<score>` is one of `fail`, `hard`, `okay`,
+///    `good` and `easy`.
+/// 2. By exiting with status `10 + n`, where `n` is the number of the score (`10` for `fail` to
+///    `14` for `easy`). This suggests the score.
+///
+/// If both are used, the result file takes precedence.
 #[derive(Debug)]
 pub struct Command(pub String);
 
+/// The exit status corresponding to the score `fail`.
+///
+/// The statuses of the other scores follow consecutively.
+const SCORE_EXIT_STATUS: i32 = 10;
+
+/// The outcome of running a command.
+#[derive(Debug)]
+pub struct CommandOutcome {
+    /// The exit status of the command.
+    pub status: process::ExitStatus,
+    /// The score reported by the command, if any.
+    pub score: Option<Score>,
+    /// Should the card be reviewed with `score` without confirmation from the user?
+    pub automatic: bool,
+    /// Was the command killed for exceeding the card's timeout?
+    pub timed_out: bool,
+    /// The standard error output of the command.
+    ///
+    /// This may be incomplete if the command timed out.
+    pub stderr: String,
+}
+
+/// Get the name of the environment variable holding the value of the card field `key`.
+///
+/// This is `CARD_FIELD_` followed by the key in upper case, with characters other than letters and
+/// digits replaced by underscores, e.g. `CARD_FIELD_SOURCE_PAGE` for `source page`.
+pub fn field_variable(key: &str) -> String {
+    let key: String = key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("CARD_FIELD_{}", key)
+}
+
+/// A private temporary directory, which is removed with its content when dropped.
+struct TempDir(path::PathBuf);
+
+impl TempDir {
+    /// Create a new directory in the system's temporary directory.
+    ///
+    /// The directory gets a random name and is only accessible by the current user, so other users
+    /// can neither read nor plant files in it.
+    fn new() -> io::Result<TempDir> {
+        let mut attempts = 0;
+        loop {
+            let path = env::temp_dir().join(format!("mu-{}-{:016x}", process::id(), rand::random::<u64>()));
+            // This fails rather than reusing a directory (or symlink) someone else created.
+            match fs::DirBuilder::new().mode(0o700).create(&path) {
+                Ok(()) => return Ok(TempDir(path)),
+                Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists && attempts < 10 => attempts += 1,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        // Ignore errors, as there is nothing sensible to do about them.
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Check if mu's process group is the foreground process group of the terminal on the standard
+/// input, i.e. if mu may read the terminal.
+fn owns_terminal() -> bool {
+    // SAFETY: These functions have no memory safety requirements.
+    unsafe { libc::isatty(libc::STDIN_FILENO) == 1 && libc::tcgetpgrp(libc::STDIN_FILENO) == libc::getpgrp() }
+}
+
+/// Make `group` the foreground process group of the terminal on the standard input.
+///
+/// This works from background process groups as well, which would otherwise be stopped by
+/// `SIGTTOU`. It is async-signal-safe, such that it can be used between `fork` and `exec`.
+fn set_foreground(group: libc::pid_t) -> io::Result<()> {
+    // SAFETY: These functions have no memory safety requirements, and the signal disposition is
+    // restored afterwards.
+    unsafe {
+        let handler = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+        let result = libc::tcsetpgrp(libc::STDIN_FILENO, group);
+        libc::signal(libc::SIGTTOU, handler);
+        if result == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+    }
+}
+
+/// A command's process group holding the terminal.
+///
+/// The terminal is given back to mu's process group when this is dropped.
+struct Foreground;
+
+impl Drop for Foreground {
+    fn drop(&mut self) {
+        // Ignore errors, as there is nothing sensible to do about them.
+        // SAFETY: `getpgrp` has no memory safety requirements.
+        let _ = set_foreground(unsafe { libc::getpgrp() });
+    }
+}
+
+/// Get the score that the exit status `code` of a command reports, if any.
+fn exit_status_score(code: Option<i32>) -> Option<Score> {
+    match code {
+        Some(code) if code >= SCORE_EXIT_STATUS && code < SCORE_EXIT_STATUS + SCORES as i32
+            => Some(SCORES_IN_ORDER[(code - SCORE_EXIT_STATUS) as usize]),
+        _ => None,
+    }
+}
+
+/// Parse the content `result` of a result file.
+///
+/// This gives the reported score and whether the card should be reviewed without confirmation, if
+/// a score was reported.
+fn parse_result(result: &str) -> Option<(Score, bool)> {
+    let mut reported = None;
+    // Go over the lines of the result file. Later lines override earlier ones.
+    for line in result.lines() {
+        let mut split = line.trim().splitn(2, '=');
+        let (key, value) = (split.next().unwrap_or(""), split.next().unwrap_or(""));
+        let automatic = match key {
+            "MU_SCORE" => false,
+            "MU_REVIEW" => true,
+            // Ignore unknown lines.
+            _ => continue,
+        };
+        if let Ok(score) = value.trim().parse() {
+            reported = Some((score, automatic));
+        }
+    }
+
+    reported
+}
+
 impl Command {
-    /// Execute the command.
+    /// Execute the command and wait for it to finish.
+    ///
+    /// The command is given information about the card through the following environment
+    /// variables:
     ///
-    /// For good measure, we set the `$CARD_ID` environment variable to contain the card ID before
-    /// execution.
-    pub fn execute(&self) -> io::Result<process::Child> {
-        // Set the `$CARD_ID` environment variable, such that the script, we execute can tell what
-        // card it is.
-        env::set_var("CARD_ID", &self.0);
+    /// - `$CARD_ID`: the card's ID.
+    /// - `$CARD_TAGS`: the card's tags, separated by commas.
+    /// - `$CARD_PRIORITY`: the card's priority (1-5, as written in the deck).
+    /// - `$CARD_STATE`: the card's state (`new`, `learning`, `relearning` or `learnt`).
+    /// - `$MU_DECK_DIR`: the directory of the deck file.
+    /// - `$MU_RESULT_FILE`: the file to which the result of the review can be written.
+    /// - `$CARD_FIELD_<KEY>`: the value of each of the card's fields (see `field_variable`).
+    ///
+    /// The variables are only set for the command, not for mu itself. The command runs in the
+    /// card's command directory (or the deck's directory, if the card has none) and is killed, along
+    /// with the processes it started, if it exceeds the card's timeout. Its standard error is
+    /// passed through while running, and kept in the outcome, such that failures can be reported.
+    ///
+    /// The command can read the terminal: if the card has a timeout, its process group (which is
+    /// killed on timeout) gets the terminal while it runs, and otherwise it stays in mu's process
+    /// group.
+    pub fn execute(&self, card: &Card, metacard: &Metacard, deck_dir: &path::Path) -> io::Result<CommandOutcome> {
+        // Create an empty file for the command to report its result in. It is in a private
+        // directory, which is removed when this returns.
+        let result_dir = TempDir::new()?;
+        let result_file = result_dir.0.join("result");
+        fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&result_file)?;
 
         // Run the command in a shell.
-        process::Command::new("/bin/sh")
+        let mut command = process::Command::new("/bin/sh");
+        command
             .arg("-c")
             .arg(&self.0)
-            .spawn()
+            .current_dir(match card.command_dir {
+                // Relative directories are relative to the deck.
+                Some(ref dir) => deck_dir.join(dir),
+                None => deck_dir.to_path_buf(),
+            })
+            .env("CARD_ID", &metacard.id)
+            .env("CARD_TAGS", card.tags.join(","))
+            .env("CARD_PRIORITY", (card.priority + 1).to_string())
+            .env("CARD_STATE", metacard.state.to_string())
+            .env("MU_DECK_DIR", deck_dir)
+            .env("MU_RESULT_FILE", &result_file)
+            .envs(card.fields.iter().map(|(key, value)| (field_variable(key), value)))
+            .stderr(process::Stdio::piped());
+        let timeout = card.command_timeout.and_then(|timeout| timeout.to_std().ok());
+        let mut foreground = None;
+        if timeout.is_some() {
+            // Put the command in its own process group, such that the processes it spawns can be
+            // killed along with it.
+            command.process_group(0);
+            // Processes outside of the foreground process group are stopped when reading the
+            // terminal, so the group gets the terminal if mu has it. This is done by the command
+            // itself before it starts, such that it cannot read the terminal too early.
+            if owns_terminal() {
+                // SAFETY: The closure only calls async-signal-safe functions.
+                unsafe {
+                    command.pre_exec(|| set_foreground(libc::getpgrp()));
+                }
+                // Take the terminal back when done, even if spawning the command failed.
+                foreground = Some(Foreground);
+            }
+        }
+        let mut child = command.spawn()?;
+
+        // Pass the standard error through while keeping a copy of it. We can safely unwrap, since
+        // it was piped above.
+        let child_stderr = child.stderr.take().unwrap();
+        let (stderr_sender, stderr_receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stderr = String::new();
+            for line in io::BufReader::new(child_stderr).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                eprintln!("{}", line);
+                stderr.push_str(&line);
+                stderr.push('\n');
+            }
+
+            // The receiver may have given up waiting, so ignore errors.
+            let _ = stderr_sender.send(stderr);
+        });
+
+        // Wait for the command to finish, killing it if it exceeds the timeout.
+        let mut timed_out = false;
+        let status = match timeout {
+            None => child.wait()?,
+            Some(timeout) => {
+                let start = time::Instant::now();
+                loop {
+                    if let Some(status) = child.try_wait()? {
+                        break status;
+                    } else if start.elapsed() > timeout {
+                        // Kill the whole process group (whose ID is the one of the shell).
+                        // SAFETY: `kill` has no memory safety requirements.
+                        if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } != 0 {
+                            return Err(io::Error::last_os_error());
+                        }
+                        timed_out = true;
+                        break child.wait()?;
+                    }
+                    thread::sleep(time::Duration::from_millis(20));
+                }
+            },
+        };
+        drop(foreground);
+        // Collect the standard error. If the command timed out, processes that left its process
+        // group might still hold the pipe open, so we only wait for it briefly.
+        let stderr = if timed_out {
+            stderr_receiver.recv_timeout(time::Duration::from_millis(100)).unwrap_or_default()
+        } else {
+            stderr_receiver.recv().unwrap_or_default()
+        };
+
+        // Read the reported result. The command may have removed the file, which means that it
+        // reported nothing.
+        let result = match fs::read_to_string(&result_file) {
+            Ok(result) => result,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        // The result file takes precedence over the exit status.
+        let (score, automatic) = match parse_result(&result) {
+            Some((score, automatic)) => (Some(score), automatic),
+            None => (exit_status_score(status.code()), false),
+        };
+
+        Ok(CommandOutcome {
+            status,
+            score,
+            automatic,
+            timed_out,
+            stderr,
+        })
     }
 }
 
@@ -153,6 +474,34 @@ pub struct Card {
     ///
     /// If the calculated interval exceeds bound, the given interval will be this duration.
     pub max_interval: chrono::Duration,
+    /// The accepted answers, if the answer is to be typed in.
+    ///
+    /// If this is nonempty, the user is prompted for an answer, which is graded against these.
+    pub answers: Vec<answer::Answer>,
+    /// The working directory of the card's commands, relative to the deck's directory.
+    ///
+    /// If this is `None`, the commands run in the deck's directory.
+    pub command_dir: Option<String>,
+    /// The time the card's commands may run before they are killed.
+    pub command_timeout: Option<chrono::Duration>,
+    /// The tag whose settings apply to the card, overriding the choice based on its tags.
+    ///
+    /// The default settings are named `""`.
+    pub settings: Option<String>,
+    /// Tag settings overridden for this card only, as key-value pairs in `.mu` format.
+    pub overrides: Vec<(String, String)>,
+    /// The IDs of the cards that must be learnt before this card is introduced.
+    pub requires: Vec<CardId>,
+    /// The position of the card in the order of introduction, if given by the user.
+    ///
+    /// Cards with positions are introduced before the others, from the lowest position.
+    pub position: Option<i64>,
+    /// The index of the card among the cards of the deck, in the order of the deck file(s).
+    pub index: usize,
+    /// User-defined fields (such as `field source: Hatcher p. 42`), by key.
+    ///
+    /// The keys are given without the `field` prefix.
+    pub fields: BTreeMap<String, String>,
 }
 
 impl Default for Card {
@@ -164,7 +513,39 @@ impl Default for Card {
             priority: 2,
             // Default to no maximal interval.
             max_interval: chrono::Duration::max_value(),
+            answers: Vec::new(),
+            command_dir: None,
+            command_timeout: None,
+            settings: None,
+            overrides: Vec::new(),
+            requires: Vec::new(),
+            position: None,
+            index: 0,
+            fields: BTreeMap::new(),
+        }
+    }
+}
+
+impl Card {
+    /// Get the tags of the card along with their ancestors, without duplicates.
+    pub fn tags_with_ancestors(&self) -> Vec<&str> {
+        let mut tags = Vec::new();
+        for tag in &self.tags {
+            for ancestor in tag_ancestors(tag) {
+                if !tags.contains(&ancestor) {
+                    tags.push(ancestor);
+                }
+            }
         }
+
+        tags
+    }
+
+    /// Grade a typed answer to the card.
+    ///
+    /// This should only be used when `self.answers` is nonempty.
+    pub fn grade(&self, given: &str) -> answer::Grade {
+        answer::grade(&self.answers, given)
     }
 }
 
@@ -380,3 +761,173 @@ impl Metacard {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::os::unix::io::FromRawFd;
+    use std::ptr;
+
+    #[test]
+    fn typed_answers() {
+        let card = Card {
+            answers: vec![answer::Answer::text("Zorn's lemma"), answer::Answer::regex("zorn").unwrap()],
+            ..Card::default()
+        };
+        assert_eq!(card.grade("zorn's  Lemma").score, Score::Good);
+        assert_eq!(card.grade("Zorn").score, Score::Good);
+        assert_eq!(card.grade("Zorns lemma").score, Score::Hard);
+        assert_eq!(card.grade("axiom of choice").score, Score::Fail);
+    }
+
+    #[test]
+    fn result_file() {
+        assert_eq!(parse_result(""), None);
+        assert_eq!(parse_result("MU_SCORE=good\n"), Some((Score::Good, false)));
+        assert_eq!(parse_result(" MU_REVIEW= hard \n"), Some((Score::Hard, true)));
+        // Later lines override earlier ones, and invalid lines are ignored.
+        assert_eq!(parse_result("MU_REVIEW=fail\nMU_SCORE=easy\nMU_SCORE=best\nnonsense"), Some((Score::Easy, false)));
+    }
+
+    #[test]
+    fn exit_statuses() {
+        assert_eq!(exit_status_score(Some(0)), None);
+        assert_eq!(exit_status_score(None), None);
+        assert_eq!(exit_status_score(Some(9)), None);
+        assert_eq!(exit_status_score(Some(10)), Some(Score::Fail));
+        assert_eq!(exit_status_score(Some(12)), Some(Score::Okay));
+        assert_eq!(exit_status_score(Some(14)), Some(Score::Easy));
+        assert_eq!(exit_status_score(Some(15)), None);
+    }
+
+    #[test]
+    fn reported_scores() {
+        let card = Card {
+            tags: vec!["Algebra".to_string(), "Theorem".to_string()],
+            ..Card::default()
+        };
+        let metacard = Metacard::new("a".to_string(), &settings::TagSettings::default());
+        // The command gets the card's information.
+        let outcome = Command("[ \"$CARD_ID $CARD_TAGS $CARD_PRIORITY $CARD_STATE\" = 'a Algebra,Theorem 3 new' ] \
+            && echo MU_REVIEW=easy > \"$MU_RESULT_FILE\"".to_string())
+            .execute(&card, &metacard, &env::temp_dir())
+            .unwrap();
+        assert_eq!((outcome.score, outcome.automatic), (Some(Score::Easy), true));
+        // The result file takes precedence over the exit status.
+        let outcome = Command("echo MU_SCORE=hard > \"$MU_RESULT_FILE\"; exit 14".to_string())
+            .execute(&card, &metacard, &env::temp_dir())
+            .unwrap();
+        assert_eq!((outcome.score, outcome.automatic), (Some(Score::Hard), false));
+        let outcome = Command("exit 11".to_string()).execute(&card, &metacard, &env::temp_dir()).unwrap();
+        assert_eq!((outcome.score, outcome.automatic), (Some(Score::Hard), false));
+    }
+
+    #[test]
+    fn command_environment() {
+        let deck_dir = env::temp_dir().join(format!("mu-command-{}", process::id()));
+        fs::create_dir_all(deck_dir.join("sub")).unwrap();
+        fs::write(deck_dir.join("sub").join("here"), "").unwrap();
+        let card = Card {
+            command_dir: Some("sub".to_string()),
+            command_timeout: Some(chrono::Duration::seconds(30)),
+            ..Card::default()
+        };
+        let metacard = Metacard::new("a".to_string(), &settings::TagSettings::default());
+        // The command runs in the card's directory, and its standard error is kept.
+        let outcome = Command("echo oops >&2; [ -f here ] && exit 3".to_string())
+            .execute(&card, &metacard, &deck_dir);
+        fs::remove_dir_all(&deck_dir).unwrap();
+        let outcome = outcome.unwrap();
+        assert_eq!(outcome.status.code(), Some(3));
+        assert_eq!(outcome.stderr, "oops\n");
+        assert!(!outcome.timed_out);
+        // The variables are only set for the command.
+        assert!(env::var_os("MU_RESULT_FILE").is_none());
+    }
+
+    #[test]
+    fn removed_result_file() {
+        let card = Card::default();
+        let metacard = Metacard::new("a".to_string(), &settings::TagSettings::default());
+        let outcome = Command("rm \"$MU_RESULT_FILE\"; exit 13".to_string())
+            .execute(&card, &metacard, &env::temp_dir())
+            .unwrap();
+        assert_eq!(outcome.score, Some(Score::Good));
+    }
+
+    #[test]
+    fn timeout_kills_process_group() {
+        let card = Card {
+            command_timeout: Some(chrono::Duration::milliseconds(200)),
+            ..Card::default()
+        };
+        let metacard = Metacard::new("a".to_string(), &settings::TagSettings::default());
+        let start = time::Instant::now();
+        // The background process holds the standard error open until it is killed.
+        let outcome = Command("echo started >&2; sleep 30 & wait".to_string())
+            .execute(&card, &metacard, &env::temp_dir())
+            .unwrap();
+        assert!(outcome.timed_out);
+        assert!(start.elapsed() < time::Duration::from_secs(10));
+        assert_eq!(outcome.stderr, "started\n");
+    }
+
+    #[test]
+    fn terminal_input() {
+        let metacard = Metacard::new("a".to_string(), &settings::TagSettings::default());
+        if env::var_os("MU_TEST_TERMINAL").is_some() {
+            // This runs in a new session, whose controlling terminal is the standard input. Commands
+            // read the terminal both with a timeout (in their own process group) and without.
+            for &timeout in &[None, Some(chrono::Duration::seconds(10))] {
+                let card = Card {
+                    command_timeout: timeout,
+                    ..Card::default()
+                };
+                let outcome = Command("read score && echo \"MU_SCORE=$score\" > \"$MU_RESULT_FILE\"".to_string())
+                    .execute(&card, &metacard, &env::temp_dir())
+                    .unwrap();
+                assert_eq!(outcome.score, Some(Score::Good));
+                assert!(!outcome.timed_out);
+                // mu got the terminal back.
+                assert!(owns_terminal());
+            }
+            return;
+        }
+
+        // Run this test again in a new session with a pseudoterminal.
+        let (mut master, mut slave) = (0, 0);
+        // SAFETY: The pointers are valid, and the optional ones may be null.
+        assert_eq!(unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), ptr::null()) }, 0);
+        // SAFETY: The descriptors were just opened, and are owned by nothing else.
+        let (mut master, slave) = unsafe { (fs::File::from_raw_fd(master), fs::File::from_raw_fd(slave)) };
+        let mut test = process::Command::new(env::current_exe().unwrap());
+        test.args(["--exact", "cards::tests::terminal_input"])
+            .env("MU_TEST_TERMINAL", "1")
+            .stdin(slave)
+            .stdout(process::Stdio::piped());
+        // SAFETY: The closure only calls async-signal-safe functions.
+        unsafe {
+            test.pre_exec(|| {
+                if libc::setsid() == -1 || libc::ioctl(libc::STDIN_FILENO, libc::TIOCSCTTY, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut test = test.spawn().unwrap();
+        master.write_all(b"good\ngood\n").unwrap();
+
+        // Commands stopped for reading the terminal would make the test hang.
+        let start = time::Instant::now();
+        while test.try_wait().unwrap().is_none() {
+            if start.elapsed() > time::Duration::from_secs(30) {
+                test.kill().unwrap();
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(20));
+        }
+        let output = test.wait_with_output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+    }
+}
@@ -1,10 +1,12 @@
 //! Collections of flashcards.
 
-use std::collections::HashMap;
-use std::{num, mem, fmt, error};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::{cmp, num, mem, fmt, error, fs, io, path};
 
 use chrono;
 
+use crate::answer;
 use crate::cards;
 use crate::settings;
 
@@ -15,30 +17,89 @@ pub struct Deck {
     pub settings: settings::GlobalSettings,
     /// The setting for the various tags.
     ///
-    /// The first tag that has associated settings will apply to the card. If none of the tags have
-    /// associated settings, it will apply the settings from the tag `""`.
+    /// The settings of a tag apply to the cards with the tag or one of its descendants (see
+    /// `Deck::settings_group` for the precedence). If none of the tags have associated settings,
+    /// it will apply the settings from the tag `""`.
     pub tag_settings: HashMap<String, settings::TagSettings>,
     /// The content of the cards.
     pub cards: HashMap<cards::CardId, cards::Card>,
 }
 
-// TODO: Instead of `ParsingError::Other`, have a bunch of smaller variants.
-
 impl Deck {
     /// Parse deck from `.mu` format.
     ///
     /// This will give an error if the deck was empty.
     pub fn parse(src: &str) -> Result<Deck, ParsingErrorLine> {
+        let (deck, diagnostics) = Deck::parse_with_diagnostics(src);
+        // Give the first error, if any.
+        match diagnostics.errors.into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(deck),
+        }
+    }
+
+    /// Parse deck from `.mu` format, collecting all errors and warnings.
+    ///
+    /// Rather than stopping at the first error, the parser skips the offending line (or section)
+    /// and carries on, so the deck consists of the parts that could be parsed.
+    ///
+    /// Files cannot be included, as there is no deck file to resolve their paths relative to (use
+    /// `load_with_diagnostics` for that).
+    pub fn parse_with_diagnostics(src: &str) -> (Deck, Diagnostics) {
+        Deck::parse_file(SourceFile {
+            path: path::PathBuf::new(),
+            src: src.to_string(),
+        }, false)
+    }
+
+    /// Load the deck file at `path`, collecting all errors and warnings.
+    ///
+    /// This is like `parse_with_diagnostics`, except that files can be included, with paths relative
+    /// to the directory containing `path`. It only fails if `path` itself cannot be read.
+    pub fn load_with_diagnostics<P: AsRef<path::Path>>(path: P) -> io::Result<(Deck, Diagnostics)> {
+        let path = path.as_ref();
+        let src = fs::read_to_string(path)?;
+        Ok(Deck::parse_file(SourceFile {
+            path: path.to_path_buf(),
+            src,
+        }, true))
+    }
+
+    /// Parse the deck with the main file `file`, which may include other files if `includes` is
+    /// set.
+    fn parse_file(file: SourceFile, includes: bool) -> (Deck, Diagnostics) {
+        let mut parser = Parser {
+            includes,
+            ..Parser::default()
+        };
+        // Files cannot include the main file either.
+        if let Ok(canonical) = file.path.canonicalize() {
+            parser.including.push(canonical.clone());
+            parser.included.insert(canonical);
+        }
+        parser.diagnostics.files.push(file);
         // Parse.
-        let mut parser = Parser::default();
-        parser.parse(src)?;
+        parser.parse(0);
+        // Check that the settings named by cards exist, now that all settings are known.
+        for (group, span) in &parser.groups {
+            if !parser.deck.tag_settings.contains_key(group) {
+                parser.diagnostics.errors.push(ParsingErrorLine {
+                    err: ParsingError::UnknownSettings(group.clone()),
+                    span: *span,
+                });
+            }
+        }
+        parser.check_prerequisites();
         // Ensure that the deck is nonempty.
         if parser.deck.cards.is_empty() {
-            Err(ParsingErrorLine {
+            parser.diagnostics.errors.push(ParsingErrorLine {
                 err: ParsingError::Other("empty deck"),
-                line_num: 0,
-            })
-        } else { Ok(parser.deck) }
+                span: Span { file: 0, line: 1, column: 1, len: 0 },
+            });
+        }
+        parser.warn();
+
+        (parser.deck, parser.diagnostics)
     }
 
     /// Clone the default tag settings.
@@ -49,17 +110,128 @@ impl Deck {
         self.tag_settings[""].clone()
     }
 
+    /// Get the tag whose settings apply to a card with tags `tags`.
+    ///
+    /// The settings of a tag apply to its descendants as well, so for each of `tags` the closest
+    /// ancestor with settings is found. Of those, the most specific (i.e. deepest) tag wins, and
+    /// the first of them in `tags` in case of a tie. If no tags have associated settings, this is
+    /// `""`.
+    pub fn settings_tag<'a>(&'a self, tags: &'a [String]) -> &'a str {
+        // The depth of a tag, i.e. the number of separators in it.
+        let depth = |tag: &str| tag.matches(cards::TAG_SEPARATOR).count();
+
+        let mut best: Option<&str> = None;
+        for tag in tags {
+            // Find the closest ancestor with settings, if any.
+            let found = cards::tag_ancestors(tag).find(|ancestor| self.tag_settings.contains_key(*ancestor));
+            match (found, best) {
+                // Keep the deeper (or earlier) tag.
+                (Some(found), Some(best)) if depth(found) <= depth(best) => (),
+                (Some(found), _) => best = Some(found),
+                (None, _) => (),
+            }
+        }
+
+        // If no tags had associated settings, use the `""` tag.
+        best.unwrap_or("")
+    }
+
     /// Get the relevant settings for a card with tags `tags`.
     pub fn tag_settings(&self, tags: &[String]) -> &settings::TagSettings {
-        // Search for the tag settings.
+        &self.tag_settings[self.settings_tag(tags)]
+    }
+
+    /// Get the tag whose settings apply to `card`, or `""` for the default settings.
+    ///
+    /// The settings are chosen in the following order of precedence:
+    ///
+    /// 1. The settings named by the card's `settings` key.
+    /// 2. The settings of the most specific of its tags (see `Deck::settings_tag`).
+    /// 3. The default settings.
+    pub fn settings_group<'a>(&'a self, card: &'a cards::Card) -> &'a str {
+        match card.settings {
+            Some(ref group) if self.tag_settings.contains_key(group) => group,
+            _ => self.settings_tag(&card.tags),
+        }
+    }
+
+    /// Get the settings that apply to `card`.
+    ///
+    /// These are the settings of its group (see `Deck::settings_group`), with the keys overridden
+    /// by the card itself replaced.
+    pub fn card_settings(&self, card: &cards::Card) -> Cow<'_, settings::TagSettings> {
+        let settings = &self.tag_settings[self.settings_group(card)];
+        if card.overrides.is_empty() {
+            return Cow::Borrowed(settings);
+        }
+
+        let mut settings = settings.clone();
+        for (key, value) in &card.overrides {
+            // The overrides were checked when parsing, so invalid ones can only come from decks
+            // that failed to parse. Skip them.
+            let _ = set_tag_setting(&mut settings, key, value);
+        }
+
+        Cow::Owned(settings)
+    }
+
+    /// Write the deck in `.mu` format.
+    ///
+    /// This is the inverse of `parse`, except that comments, includes and the order of the
+    /// sections are lost: the global settings come first, followed by the default tag settings,
+    /// the settings of the other tags sorted by name, and the cards in the order of the deck. Only
+    /// the settings that differ from their defaults are written, and tags inherit the settings of
+    /// another tag if that saves keys.
+    pub fn to_mu_string(&self) -> String {
+        let mut sections = Vec::new();
+
+        // Write the global settings.
+        let settings = global_setting_values(&self.settings);
+        let defaults = global_setting_values(&settings::GlobalSettings::default());
+        let lines = changed(&settings, &defaults);
+        if !lines.is_empty() {
+            sections.push(section("settings", lines));
+        }
+
+        // Write the default tag settings, which the other tags start from.
+        let defaults = tag_setting_values(&self.tag_settings[""]);
+        let built_in = tag_setting_values(&settings::TagSettings::default());
+        let lines = changed(&defaults, &built_in);
+        if !lines.is_empty() {
+            sections.push(section("tag default", lines));
+        }
+
+        // Write the tag settings. Tags can only inherit settings of tags written before them.
+        let mut tags: Vec<_> = self.tag_settings.keys().filter(|tag| !tag.is_empty()).collect();
+        tags.sort();
+        let mut written: Vec<(&str, Vec<(&str, String)>)> = Vec::new();
         for tag in tags {
-            if let Some(settings) = self.tag_settings.get(tag) {
-                return settings;
+            let values = tag_setting_values(&self.tag_settings[tag]);
+            // Start from the default settings, unless inheriting those of another tag is shorter.
+            // The parser starts the first tag section after `[tag default]` from the settings
+            // before it, which are the built-in ones.
+            let mut lines = changed(&values, if written.is_empty() { &built_in } else { &defaults });
+            for &(other, ref other_values) in &written {
+                let inherited = changed(&values, other_values);
+                if inherited.len() + 1 < lines.len() {
+                    lines = inherited;
+                    lines.insert(0, format!("INHERIT: {}", other));
+                }
             }
+            sections.push(section(&format!("tag {}", tag), lines));
+            written.push((tag, values));
         }
 
-        // If no tags had associated settings, use the `""` tag.
-        &self.tag_settings[""]
+        // Write the cards in the order of the deck, which is the order new cards are introduced in
+        // by default.
+        let mut ids: Vec<_> = self.cards.keys().collect();
+        ids.sort_by_key(|&id| (self.cards[id].index, id));
+        for id in ids {
+            sections.push(section(&format!("card {}", id), card_lines(id, &self.cards[id])));
+        }
+
+        // Separate the sections by blank lines.
+        sections.join("\n")
     }
 }
 
@@ -91,31 +263,258 @@ fn key_value(s: &str) -> Result<(&str, &str), ParsingError> {
     }
 }
 
+/// The units of durations, along with their lengths in seconds, from the longest to the shortest.
+///
+/// Months and years have their average length in the Gregorian calendar, i.e. a year is 365.2425
+/// days and a month is a twelfth of that.
+pub const DURATION_UNITS: [(&str, i64); 7] = [
+    ("y", 31_556_952),
+    ("M", 2_629_746),
+    ("w", 7 * 24 * 60 * 60),
+    ("d", 24 * 60 * 60),
+    ("h", 60 * 60),
+    ("m", 60),
+    ("s", 1),
+];
+
 /// Parse duration.
 ///
-/// This parses a string of form `<integer><unit>` where `<unit>` is one of the following:
+/// This parses a string of one or more parts of the form `<number><unit>`, optionally separated by
+/// whitespace (e.g. `1d 12h` or `1h30m`), where `<number>` may be fractional (e.g. `1.5d`) and
+/// `<unit>` is one of the following:
 ///
+/// - `s` for second.
 /// - `m` for minute.
+/// - `h` for hour.
 /// - `d` for day.
 /// - `w` for week.
 /// - `M` for month.
 /// - `y` for year.
+///
+/// The duration is rounded to whole seconds.
 fn parse_duration(s: &str) -> Result<chrono::Duration, ParsingError> {
-    // Find the length of the number part of `s` and the unit (the last character of `s`).
-    let (number_part, unit) = s.char_indices().last().ok_or(ParsingError::Other("duration empty"))?;
-    // Parse the number part.
-    let number = s[..number_part].parse()?;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(ParsingError::Other("duration empty"));
+    }
+
+    // Add up the parts.
+    let mut seconds = 0.0;
+    while !rest.is_empty() {
+        // Split off the number.
+        let number_len = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+        let number: f64 = rest[..number_len].parse()?;
+        rest = &rest[number_len..];
+        // Split off the unit following it.
+        let unit_len = rest.find(|c: char| !c.is_alphabetic()).unwrap_or(rest.len());
+        let unit = &rest[..unit_len];
+        rest = rest[unit_len..].trim_start();
+
+        let &(_, length) = DURATION_UNITS.iter()
+            .find(|&&(name, _)| name == unit)
+            .ok_or_else(|| ParsingError::UnknownUnit {
+                duration: s.to_string(),
+                unit: unit.to_string(),
+            })?;
+        seconds += number * length as f64;
+    }
+
+    chrono::Duration::try_seconds(seconds.round() as i64).ok_or(ParsingError::Other("duration too long"))
+}
+
+/// Format a duration, such that `parse_duration` gives it back.
+fn format_duration(duration: chrono::Duration) -> String {
+    let mut seconds = duration.num_seconds();
+    if seconds == 0 {
+        return "0m".to_string();
+    }
+    // Use the largest unit the duration is a multiple of, if any (seconds would always do).
+    for &(unit, length) in &DURATION_UNITS[..DURATION_UNITS.len() - 1] {
+        if seconds % length == 0 {
+            return format!("{}{}", seconds / length, unit);
+        }
+    }
+
+    // Otherwise, combine units, leaving out months and years as they are not whole days.
+    let mut parts = Vec::new();
+    for &(unit, length) in &DURATION_UNITS[2..] {
+        if seconds >= length {
+            parts.push(format!("{}{}", seconds / length, unit));
+            seconds %= length;
+        }
+    }
+
+    parts.join(" ")
+}
+
+/// Format a comma-separated list, such that `parse_list` gives it back.
+fn format_list<T: fmt::Display>(items: &[T]) -> String {
+    items.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+}
 
-    // Convert into `chrono::Duration` accordingly.
-    match unit {
-        'm' => Ok(chrono::Duration::minutes(number)),
-        'd' => Ok(chrono::Duration::days(number)),
-        'w' => Ok(chrono::Duration::weeks(number)),
-        'M' => Ok(chrono::Duration::weeks(number * 4)),
-        'y' => Ok(chrono::Duration::weeks(number * 4 * 12)),
-        // TODO: Better error message. Output `unit`.
-        _   => Err(ParsingError::Other("unknown unit")),
+/// Format a section with title `title` and key-value pairs `lines`.
+fn section(title: &str, lines: Vec<String>) -> String {
+    let mut section = format!("[{}]\n", title);
+    for line in lines {
+        section.push_str(&line);
+        section.push('\n');
     }
+
+    section
+}
+
+/// Get the key-value pairs of `values` that differ from `defaults`.
+///
+/// Both must be the values of the same keys, in the same order.
+fn changed(values: &[(&str, String)], defaults: &[(&str, String)]) -> Vec<String> {
+    values.iter()
+        .zip(defaults)
+        .filter(|&(value, default)| value != default)
+        .map(|(&(key, ref value), _)| format!("{}: {}", key, value))
+        .collect()
+}
+
+/// Get the formatted values of the global settings `settings`, by key.
+fn global_setting_values(settings: &settings::GlobalSettings) -> Vec<(&'static str, String)> {
+    vec![
+        ("max new queue", settings.max_new_queue.to_string()),
+        ("max new daily", settings.max_new_daily.to_string()),
+        ("min new probability", settings.min_new_probability.to_string()),
+        ("new card order", settings.new_card_order.to_string()),
+    ]
+}
+
+/// Get the formatted values of the tag settings `settings`, by key.
+fn tag_setting_values(settings: &settings::TagSettings) -> Vec<(&'static str, String)> {
+    // The intervals are written with units.
+    let learning_intervals: Vec<_> = settings.learning_intervals.iter().map(|&d| format_duration(d)).collect();
+    let relearning_intervals: Vec<_> = settings.relearning_intervals.iter().map(|&d| format_duration(d)).collect();
+    vec![
+        ("learning intervals", format_list(&learning_intervals)),
+        ("learning interval progressions", format_list(&settings.learning_interval_progressions)),
+        ("relearning intervals", format_list(&relearning_intervals)),
+        ("relearning interval progressions", format_list(&settings.relearning_interval_progressions)),
+        ("max interval", format_duration(settings.max_interval)),
+        ("min interval increase", format_duration(settings.min_interval_increase)),
+        ("starting ease", settings.starting_ease.to_string()),
+        ("min ease", settings.min_ease.to_string()),
+        ("max ease", settings.max_ease.to_string()),
+        ("ease increase", format_list(&settings.ease_increase)),
+        ("interval modifier", settings.interval_modifier.to_string()),
+        ("score modifiers", format_list(&settings.score_modifiers)),
+        ("priority modifiers", format_list(&settings.priority_modifiers)),
+        ("score weight", settings.score_weight.to_string()),
+        ("familiarity delta", settings.familiarity_delta.to_string()),
+        ("max familiarity", settings.max_familiarity.to_string()),
+        ("min familiarity", settings.min_familiarity.to_string()),
+        ("desired retention rate", settings.desired_retention_rate.to_string()),
+    ]
+}
+
+/// Get the key-value pairs of the card `card` with ID `id`, leaving out default values.
+fn card_lines(id: &str, card: &cards::Card) -> Vec<String> {
+    let default = cards::Card::default();
+    let mut lines = Vec::new();
+
+    // The PDF named after the ID is implied if there are no other views.
+    match card.view[..] {
+        [cards::View::Pdf(ref pdf)] if *pdf == format!("{}.pdf", id) => (),
+        _ => for view in &card.view {
+            lines.push(match *view {
+                cards::View::Pdf(ref pdf) => format!("pdf: {}", pdf),
+                cards::View::Command(ref command) => format!("sh: {}", command.0),
+            });
+        },
+    }
+    if let Some(ref dir) = card.command_dir {
+        lines.push(format!("sh dir: {}", dir));
+    }
+    if let Some(timeout) = card.command_timeout {
+        lines.push(format!("sh timeout: {}", format_duration(timeout)));
+    }
+    if !card.tags.is_empty() {
+        lines.push(format!("tags: {}", format_list(&card.tags)));
+    }
+    if card.max_interval != default.max_interval {
+        lines.push(format!("max interval: {}", format_duration(card.max_interval)));
+    }
+    for answer in &card.answers {
+        lines.push(match *answer {
+            answer::Answer::Text(..) => format!("answer: {}", answer.source()),
+            answer::Answer::Regex(..) => format!("answer regex: {}", answer.source()),
+        });
+    }
+    if card.priority != default.priority {
+        // Priorities are 1-based in the deck.
+        lines.push(format!("priority: {}", card.priority + 1));
+    }
+    match card.settings.as_deref() {
+        Some("") => lines.push("settings: default".to_string()),
+        Some(group) => lines.push(format!("settings: {}", group)),
+        None => (),
+    }
+    if !card.requires.is_empty() {
+        lines.push(format!("requires: {}", format_list(&card.requires)));
+    }
+    if let Some(position) = card.position {
+        lines.push(format!("position: {}", position));
+    }
+    for (key, value) in &card.overrides {
+        lines.push(format!("{}: {}", key, value));
+    }
+    for (key, value) in &card.fields {
+        lines.push(format!("{}{}: {}", FIELD_PREFIX, key, value));
+    }
+
+    lines
+}
+
+/// Find the cycles of prerequisites reachable from the card `id`, which is required by the cards
+/// of `path` in turn.
+///
+/// Each cycle is pushed to `cycles` as a list of cards, each requiring the next and the last
+/// requiring the first. Cards whose prerequisites have been searched completely are added to `done`
+/// and skipped, such that each cycle is only found once.
+fn prerequisite_cycles<'a>(
+    cards: &'a HashMap<cards::CardId, cards::Card>,
+    id: &'a str,
+    path: &mut Vec<&'a str>,
+    done: &mut HashSet<&'a str>,
+    cycles: &mut Vec<Vec<&'a str>>,
+) {
+    if done.contains(id) {
+        return;
+    }
+    // Stop if the card requires itself (indirectly).
+    if let Some(start) = path.iter().position(|&card| card == id) {
+        cycles.push(path[start..].to_vec());
+        return;
+    }
+    // Unknown prerequisites are reported separately.
+    let card = match cards.get(id) {
+        Some(card) => card,
+        None => return,
+    };
+
+    path.push(id);
+    for required in &card.requires {
+        prerequisite_cycles(cards, required, path, done, cycles);
+    }
+    path.pop();
+    done.insert(id);
+}
+
+/// Get the path of the file included by the line `line`, if it is an include directive.
+///
+/// Include directives are of the form `[include <path>]`, with `<path>` relative to the including
+/// file.
+pub fn included(line: &str) -> Option<&str> {
+    let line = line.trim();
+    if line.starts_with('[') && line.ends_with(']') {
+        return line[1..line.len() - 1].trim().strip_prefix("include ").map(str::trim_start);
+    }
+
+    None
 }
 
 /// Parse comma-separated list.
@@ -157,7 +556,115 @@ fn to_score_array<T: Default + Copy>(vec: Vec<T>)
 
         Ok(arr)
     } else {
-        Err(ParsingError::Other("wrong number of items in the list (expected the number of scores)"))
+        Err(ParsingError::WrongLength {
+            expected: cards::SCORES,
+            actual: vec.len(),
+        })
+    }
+}
+
+/// The keys of the global settings.
+const GLOBAL_KEYS: &[&str] = &["max new queue", "max new daily", "min new probability", "new card order"];
+
+/// The keys of tag settings.
+const TAG_KEYS: &[&str] = &[
+    "INHERIT",
+    "learning intervals",
+    "learning interval progressions",
+    "relearning intervals",
+    "relearning interval progressions",
+    "max interval",
+    "min interval increase",
+    "starting ease",
+    "min ease",
+    "max ease",
+    "ease increase",
+    "interval modifier",
+    "score modifiers",
+    "priority modifiers",
+    "score weight",
+    "familiarity delta",
+    "max familiarity",
+    "min familiarity",
+    "desired retention rate",
+];
+
+/// The prefix of the keys of user-defined card fields, such as `field source`.
+const FIELD_PREFIX: &str = "field ";
+
+/// The keys of cards.
+///
+/// Besides these, cards can override the keys of tag settings (see `overridable`) and have fields
+/// with keys starting with `FIELD_PREFIX`.
+const CARD_KEYS: &[&str] = &[
+    "pdf",
+    "sh",
+    "sh dir",
+    "sh timeout",
+    "tags",
+    "max interval",
+    "answer",
+    "answer regex",
+    "priority",
+    "settings",
+    "requires",
+    "position",
+];
+
+/// Check if cards can override the tag settings key `key`.
+///
+/// This is not the case for `INHERIT` and for keys that are card keys already.
+fn overridable(key: &str) -> bool {
+    key != "INHERIT" && TAG_KEYS.contains(&key) && !CARD_KEYS.contains(&key)
+}
+
+/// Set the key `key` of the tag settings `settings` to `value`.
+///
+/// `INHERIT` must be handled by the caller.
+fn set_tag_setting(settings: &mut settings::TagSettings, key: &str, value: &str) -> Result<(), ParsingError> {
+    match key {
+        "learning intervals" => settings.learning_intervals = parse_list(value, parse_duration)?,
+        "learning interval progressions"
+            => settings.learning_interval_progressions = to_score_array(parse_list(value, str::parse)?)?,
+        "relearning intervals" => settings.relearning_intervals = parse_list(value, parse_duration)?,
+        "relearning interval progressions"
+            => settings.relearning_interval_progressions = to_score_array(parse_list(value, str::parse)?)?,
+        "max interval" => settings.max_interval = parse_duration(value)?,
+        "min interval increase" => settings.min_interval_increase = parse_duration(value)?,
+        "starting ease" => settings.starting_ease = value.parse()?,
+        "min ease" => settings.min_ease = value.parse()?,
+        "max ease" => settings.max_ease = value.parse()?,
+        "ease increase" => settings.ease_increase = to_score_array(parse_list(value, str::parse)?)?,
+        "interval modifier" => settings.interval_modifier = value.parse()?,
+        "score modifiers" => settings.score_modifiers = to_score_array(parse_list(value, str::parse)?)?,
+        "priority modifiers" => settings.priority_modifiers = to_score_array(parse_list(value, str::parse)?)?,
+        "score weight" => settings.score_weight = value.parse()?,
+        "familiarity delta" => settings.familiarity_delta = value.parse()?,
+        "max familiarity" => settings.max_familiarity = value.parse()?,
+        "min familiarity" => settings.min_familiarity = value.parse()?,
+        "desired retention rate" => settings.desired_retention_rate = value.parse()?,
+        _ => return Err(unknown_key(key, TAG_KEYS)),
+    }
+
+    Ok(())
+}
+
+/// Create an error for the unknown key `key`, suggesting the closest of the valid keys `keys`.
+fn unknown_key(key: &str, keys: &[&'static str]) -> ParsingError {
+    let key_chars: Vec<char> = key.chars().collect();
+    let suggestion = keys.iter()
+        .map(|&candidate| {
+            let candidate_chars: Vec<char> = candidate.chars().collect();
+            (answer::edit_distance(&key_chars, &candidate_chars), candidate)
+        })
+        // Only suggest keys that are close enough to be a typo.
+        .filter(|&(distance, candidate)| distance <= cmp::max(1, candidate.len() / 3))
+        .min()
+        .map(|(_, candidate)| candidate);
+
+    ParsingError::UnknownKey {
+        key: key.to_string(),
+        suggestion,
     }
 }
 
@@ -177,6 +684,10 @@ enum ParserState {
     Card(cards::CardId),
     /// The parser has just been flushed and waits for a new state.
     Flushed,
+    /// Currently skipping an invalid section.
+    ///
+    /// Its lines are ignored until the next section, to avoid an error for each of them.
+    Skipped,
 }
 
 impl Default for ParserState {
@@ -194,10 +705,218 @@ pub enum ParsingError {
     ParseInt(num::ParseIntError),
     /// Error during float parsing.
     ParseFloat(num::ParseFloatError),
+    /// Error during regular expression parsing.
+    Regex(regex::Error),
+    /// A key that is not valid in the section.
+    UnknownKey {
+        /// The key.
+        key: String,
+        /// The valid key closest to `key`, if any is close.
+        suggestion: Option<&'static str>,
+    },
+    /// A duration with an unknown unit.
+    UnknownUnit {
+        /// The duration.
+        duration: String,
+        /// The unit.
+        unit: String,
+    },
+    /// A list with the wrong number of items.
+    WrongLength {
+        /// The number of items expected.
+        expected: usize,
+        /// The number of items given.
+        actual: usize,
+    },
+    /// A section whose title is not recognized.
+    UnknownSection(String),
+    /// A section that appeared before.
+    DuplicateSection {
+        /// The title of the section.
+        title: String,
+        /// The (1-based) line on which it first appeared.
+        first_line: usize,
+        /// The file in which it first appeared, if it is another file.
+        first_file: Option<path::PathBuf>,
+    },
+    /// An included file that could not be read.
+    Include {
+        /// The path of the file.
+        path: path::PathBuf,
+        /// The error reading it.
+        err: io::Error,
+    },
+    /// A file that (directly or indirectly) includes itself.
+    IncludeCycle(path::PathBuf),
+    /// Settings of a card naming a tag without settings.
+    UnknownSettings(String),
+    /// A prerequisite of a card that is not in the deck.
+    UnknownPrerequisite(String),
+    /// Cards that require each other, each requiring the next and the last requiring the first.
+    PrerequisiteCycle(Vec<cards::CardId>),
     /// Other error.
     Other(&'static str),
 }
 
+/// A location in a `.mu` file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    /// The index of the file in `Diagnostics::files`.
+    pub file: usize,
+    /// The (1-based) line number.
+    pub line: usize,
+    /// The (1-based) column, in characters.
+    pub column: usize,
+    /// The length, in characters.
+    pub len: usize,
+}
+
+impl Span {
+    /// Find the part of `line` (with number `line_num` in file `file`) that `err` is about.
+    fn locate(file: usize, line_num: usize, line: &str, err: &ParsingError) -> Span {
+        // The part of the line following the colon of a key-value pair, if any.
+        let value_start = line.find(':').map(|colon| colon + 1);
+        // Get the byte range.
+        let (start, part) = match *err {
+            ParsingError::UnknownKey { ref key, .. } => (line.find(key.as_str()), key.as_str()),
+            ParsingError::UnknownUnit { ref duration, .. } => (
+                value_start.and_then(|start| line[start..].find(duration.as_str()).map(|n| start + n)),
+                duration.as_str(),
+            ),
+            ParsingError::UnknownSection(..)
+                | ParsingError::DuplicateSection { .. }
+                | ParsingError::Include { .. }
+                | ParsingError::IncludeCycle(..) => {
+                let trimmed = line.trim();
+                (line.find(trimmed), trimmed)
+            },
+            _ => match value_start {
+                Some(start) => {
+                    let value = line[start..].trim();
+                    (line[start..].find(value).map(|n| start + n), value)
+                },
+                None => {
+                    let trimmed = line.trim();
+                    (line.find(trimmed), trimmed)
+                },
+            },
+        };
+        Span::new(file, line_num, line, start.unwrap_or(0), part)
+    }
+
+    /// Create the span of `part`, which starts at byte `start` of `line` (with number `line_num` in
+    /// file `file`).
+    fn new(file: usize, line_num: usize, line: &str, start: usize, part: &str) -> Span {
+        Span {
+            file,
+            line: line_num,
+            // Count characters rather than bytes.
+            column: line[..start].chars().count() + 1,
+            len: part.chars().count(),
+        }
+    }
+}
+
+/// Render a message about `span` in the style of rustc, with a snippet of the source `src` of the
+/// file named `file`.
+fn render(message: &str, span: Span, file: &str, src: &str) -> String {
+    let number = span.line.to_string();
+    // The margin left of the snippet.
+    let margin = " ".repeat(number.len());
+    let mut rendered = format!("{}\n{}--> {}:{}:{}", message, margin, file, span.line, span.column);
+    // Show the line, unless the span is at the end of the file.
+    if let Some(line) = src.lines().nth(span.line - 1) {
+        rendered.push_str(&format!("\n{} |\n{} | {}\n{} | {}{}",
+            margin,
+            number,
+            // Tabs would throw off the alignment of the marker.
+            line.replace('\t', " "),
+            margin,
+            " ".repeat(span.column - 1),
+            "^".repeat(cmp::max(1, span.len)),
+        ));
+    }
+
+    rendered
+}
+
+/// A likely mistake in a deck, which does not make it invalid.
+#[derive(Debug)]
+pub enum Warning {
+    /// A card without tags.
+    Untagged {
+        /// The ID of the card.
+        id: cards::CardId,
+        /// The location of the section of the card.
+        span: Span,
+    },
+    /// Settings for a tag which no card has.
+    UnusedTag {
+        /// The tag.
+        tag: String,
+        /// The location of the section of the tag.
+        span: Span,
+    },
+}
+
+impl Warning {
+    /// The location of the warning.
+    pub fn span(&self) -> Span {
+        match *self {
+            Warning::Untagged { span, .. } | Warning::UnusedTag { span, .. } => span,
+        }
+    }
+
+    /// Render the warning in the style of rustc, with a snippet of the source `src` of the file
+    /// named `file`.
+    pub fn render(&self, file: &str, src: &str) -> String {
+        render(&self.to_string(), self.span(), file, src)
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Warning::Untagged { ref id, .. } => write!(f, "card `{}` has no tags", id),
+            Warning::UnusedTag { ref tag, .. } => write!(f, "tag `{}` is configured, but no card has it", tag),
+        }
+    }
+}
+
+/// A file of a deck.
+#[derive(Debug)]
+pub struct SourceFile {
+    /// The path of the file, which is empty for decks that were not loaded from a file.
+    pub path: path::PathBuf,
+    /// The content of the file.
+    pub src: String,
+}
+
+impl SourceFile {
+    /// Check if the file contains comments, which are lost when the deck is written back.
+    pub fn has_comments(&self) -> bool {
+        self.src.lines().any(|line| line.trim().starts_with('#'))
+    }
+}
+
+/// The errors and warnings found while parsing a deck.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    /// The errors, in the order they appear in the deck.
+    pub errors: Vec<ParsingErrorLine>,
+    /// The warnings, in the order they appear in the deck.
+    pub warnings: Vec<Warning>,
+    /// The files of the deck, starting with the main file, which the spans refer to.
+    pub files: Vec<SourceFile>,
+}
+
+impl Diagnostics {
+    /// Get the file that `span` is in.
+    pub fn file(&self, span: Span) -> &SourceFile {
+        &self.files[span.file]
+    }
+}
+
 impl From<num::ParseIntError> for ParsingError {
     fn from(error: num::ParseIntError) -> Self {
         ParsingError::ParseInt(error)
@@ -210,11 +929,17 @@ impl From<num::ParseFloatError> for ParsingError {
     }
 }
 
-/// A parsing error with an associated line number.
+impl From<regex::Error> for ParsingError {
+    fn from(error: regex::Error) -> Self {
+        ParsingError::Regex(error)
+    }
+}
+
+/// A parsing error with an associated location.
 #[derive(Debug)]
 pub struct ParsingErrorLine {
-    /// The line number.
-    line_num: usize,
+    /// The location of the error.
+    span: Span,
     /// The error.
     err: ParsingError,
 }
@@ -228,21 +953,76 @@ impl error::Error for ParsingErrorLine {
         match self.err {
             ParsingError::ParseInt(ref err) => Some(err),
             ParsingError::ParseFloat(ref err) => Some(err),
-            ParsingError::Other(..) => None,
+            ParsingError::Regex(ref err) => Some(err),
+            ParsingError::Include { ref err, .. } => Some(err),
+            _ => None,
         }
     }
 }
 
-impl fmt::Display for ParsingErrorLine {
+impl fmt::Display for ParsingError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.err {
-            ParsingError::ParseInt(ref err) => write!(f, "failed to parse integer ({}); at line {}", err, self.line_num),
-            ParsingError::ParseFloat(ref err) => write!(f, "failed to parse float ({}); at line {}", err, self.line_num),
-            ParsingError::Other(ref err) => write!(f, "{}; at line {}", err, self.line_num),
+        match *self {
+            ParsingError::ParseInt(ref err) => write!(f, "failed to parse integer ({})", err),
+            ParsingError::ParseFloat(ref err) => write!(f, "failed to parse float ({})", err),
+            ParsingError::Regex(ref err) => write!(f, "failed to parse regular expression ({})", err),
+            ParsingError::UnknownKey { ref key, suggestion: Some(suggestion) }
+                => write!(f, "unknown key `{}` (did you mean `{}`?)", key, suggestion),
+            ParsingError::UnknownKey { ref key, suggestion: None } => write!(f, "unknown key `{}`", key),
+            ParsingError::UnknownUnit { ref duration, ref unit } if unit.is_empty()
+                => write!(f, "missing unit in duration `{}` (expected s, m, h, d, w, M or y)", duration),
+            ParsingError::UnknownUnit { ref duration, ref unit }
+                => write!(f, "unknown unit `{}` in duration `{}` (expected s, m, h, d, w, M or y)", unit, duration),
+            ParsingError::WrongLength { expected, actual }
+                => write!(f, "wrong number of items in the list (expected {}, found {})", expected, actual),
+            ParsingError::UnknownSection(ref title) => write!(f, "unknown section `[{}]`", title),
+            ParsingError::DuplicateSection { ref title, first_line, first_file: None }
+                => write!(f, "section `[{}]` appears multiple times (first on line {})", title, first_line),
+            ParsingError::DuplicateSection { ref title, first_line, first_file: Some(ref file) }
+                => write!(f, "section `[{}]` appears multiple times (first on line {} of `{}`)", title, first_line, file.display()),
+            ParsingError::Include { ref path, ref err }
+                => write!(f, "cannot read included file `{}` ({})", path.display(), err),
+            ParsingError::IncludeCycle(ref path)
+                => write!(f, "including `{}` would form a cycle", path.display()),
+            ParsingError::UnknownSettings(ref tag) => write!(f, "there are no settings for tag `{}`", tag),
+            ParsingError::UnknownPrerequisite(ref id) => write!(f, "there is no card `{}`", id),
+            ParsingError::PrerequisiteCycle(ref cycle) => {
+                write!(f, "cyclic prerequisites: `{}`", cycle[0])?;
+                // Follow the cycle back to the first card.
+                for (n, id) in cycle[1..].iter().chain(&cycle[..1]).enumerate() {
+                    write!(f, "{} `{}`", if n == 0 { " requires" } else { ", which requires" }, id)?;
+                }
+                Ok(())
+            },
+            ParsingError::Other(ref err) => write!(f, "{}", err),
         }
     }
 }
 
+impl ParsingErrorLine {
+    /// The location of the error.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// The error.
+    pub fn error(&self) -> &ParsingError {
+        &self.err
+    }
+
+    /// Render the error in the style of rustc, with a snippet of the source `src` of the file
+    /// named `file`.
+    pub fn render(&self, file: &str, src: &str) -> String {
+        render(&self.err.to_string(), self.span, file, src)
+    }
+}
+
+impl fmt::Display for ParsingErrorLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}; at line {}, column {}", self.err, self.span.line, self.span.column)
+    }
+}
+
 /// A `.mu`-file parser.
 #[derive(Default)]
 struct Parser {
@@ -261,23 +1041,169 @@ struct Parser {
     /// `flush()`. Note that it is only relevant when `state` is `ParsingState::CardMeta` or
     /// `ParsingState::CardSides`.
     current_card: cards::Card,
+    /// The index of the file being parsed in `diagnostics.files`.
+    file: usize,
+    /// The (1-based) number of the line being parsed.
+    line_num: usize,
+    /// Can files be included?
+    includes: bool,
+    /// The canonical paths of the files being parsed, each included by the previous one.
+    including: Vec<path::PathBuf>,
+    /// The canonical paths of all the files parsed so far.
+    included: HashSet<path::PathBuf>,
+    /// The titles of the tag and card sections seen so far, along with their locations.
+    sections: HashMap<String, Span>,
+    /// The tags whose settings are inherited by other tags.
+    inherited: HashSet<String>,
+    /// The settings named by the `settings` keys of cards, along with the locations of the names.
+    groups: Vec<(String, Span)>,
+    /// The prerequisites of cards as `(card, prerequisite)`, along with the locations of the latter.
+    prerequisites: Vec<(String, String, Span)>,
+    /// The errors and warnings found so far.
+    diagnostics: Diagnostics,
 }
 
 impl Parser {
-    /// Parse `src` and update state accordingly.
-    fn parse<'a>(&mut self, src: &'a str) -> Result<(), ParsingErrorLine> {
-        // The current line number.
-        let mut line_num = 0;
+    /// Parse the file with index `file` in `self.diagnostics.files` and update state accordingly.
+    ///
+    /// Errors are collected in `self.diagnostics`, skipping the lines they occur on.
+    fn parse(&mut self, file: usize) {
+        // Remember where we are in the including file.
+        let outer = (mem::replace(&mut self.file, file), mem::replace(&mut self.line_num, 0));
+        // Included files are parsed while parsing, so the source cannot be borrowed.
+        let src = self.diagnostics.files[file].src.clone();
+
         // Parse line-by-line.
         for line in src.lines() {
-            self.parse_line(line).map_err(|err| ParsingErrorLine { err, line_num })?;
-            line_num += 1;
+            self.line_num += 1;
+            if let Err(err) = self.parse_line(line) {
+                self.diagnostics.errors.push(ParsingErrorLine {
+                    span: Span::locate(self.file, self.line_num, line, &err),
+                    err,
+                });
+            }
+        }
+
+        // Flush the last state, as sections end with the file. Errors are reported at the end of
+        // the file.
+        if let Err(err) = self.flush() {
+            self.diagnostics.errors.push(ParsingErrorLine {
+                err,
+                span: Span { file, line: self.line_num + 1, column: 1, len: 0 },
+            });
         }
 
-        // Flush the last state.
-        self.flush().map_err(|err| ParsingErrorLine { err, line_num })
+        self.file = outer.0;
+        self.line_num = outer.1;
     }
 
+    /// Parse the file at `path`, relative to the file being parsed.
+    ///
+    /// Files that have been included already are skipped, such that several files can include a
+    /// common file.
+    fn include(&mut self, path: &str) -> Result<(), ParsingError> {
+        if !self.includes {
+            return Err(ParsingError::Other("files can only be included by deck files"));
+        }
+        // Resolve the path.
+        let path = self.diagnostics.files[self.file].path
+            .parent()
+            .unwrap_or_else(|| path::Path::new(""))
+            .join(path);
+        let read = |path: &path::Path| Ok((path.canonicalize()?, fs::read_to_string(path)?));
+        let (canonical, src) = read(&path).map_err(|err| ParsingError::Include {
+            path: path.clone(),
+            err,
+        })?;
+        // Refuse to include a file in itself.
+        if self.including.contains(&canonical) {
+            return Err(ParsingError::IncludeCycle(path));
+        }
+        if !self.included.insert(canonical.clone()) {
+            return Ok(());
+        }
+
+        // Parse the file.
+        self.diagnostics.files.push(SourceFile { path, src });
+        self.including.push(canonical);
+        self.parse(self.diagnostics.files.len() - 1);
+        self.including.pop();
+
+        Ok(())
+    }
+
+    /// Find likely mistakes in the parsed deck.
+    fn warn(&mut self) {
+        let warnings = &mut self.diagnostics.warnings;
+        // Find the cards without tags.
+        for (id, card) in &self.deck.cards {
+            if card.tags.is_empty() {
+                if let Some(&span) = self.sections.get(&format!("card {}", id)) {
+                    warnings.push(Warning::Untagged { id: id.clone(), span });
+                }
+            }
+        }
+        // Find the tags with settings that are neither used by cards (or their descendants) nor
+        // inherited.
+        for tag in self.deck.tag_settings.keys() {
+            if tag.is_empty()
+                || self.inherited.contains(tag)
+                || self.groups.iter().any(|(group, _)| group == tag)
+                || self.deck.cards.values().any(|card| card.tags.iter().any(|card_tag| cards::tag_matches(card_tag, tag)))
+            {
+                continue;
+            }
+            if let Some(&span) = self.sections.get(&format!("tag {}", tag)) {
+                warnings.push(Warning::UnusedTag { tag: tag.clone(), span });
+            }
+        }
+
+        // Make the order deterministic.
+        warnings.sort_by_key(|warning| {
+            let span = warning.span();
+            (span.file, span.line, span.column)
+        });
+    }
+
+    /// Check that the prerequisites of the cards exist and that no cards require each other.
+    ///
+    /// This is done after parsing, when all the cards are known.
+    fn check_prerequisites(&mut self) {
+        for (_, required, span) in &self.prerequisites {
+            if !self.deck.cards.contains_key(required) {
+                self.diagnostics.errors.push(ParsingErrorLine {
+                    err: ParsingError::UnknownPrerequisite(required.clone()),
+                    span: *span,
+                });
+            }
+        }
+
+        // Search for cycles from every card, in order of their IDs such that the errors are
+        // deterministic.
+        let mut cycles = Vec::new();
+        let mut done = HashSet::new();
+        let mut ids: Vec<_> = self.deck.cards.keys().collect();
+        ids.sort();
+        for id in ids {
+            prerequisite_cycles(&self.deck.cards, id, &mut Vec::new(), &mut done, &mut cycles);
+        }
+        for mut cycle in cycles {
+            // Report the cycle at the prerequisite closing it, and start with the card it belongs to.
+            let last = cycle.pop().unwrap();
+            cycle.insert(0, last);
+            let span = self.prerequisites.iter()
+                .find(|(id, required, _)| *id == cycle[0] && *required == cycle[1 % cycle.len()])
+                .map(|&(_, _, span)| span);
+            if let Some(span) = span {
+                self.diagnostics.errors.push(ParsingErrorLine {
+                    err: ParsingError::PrerequisiteCycle(cycle.into_iter().map(str::to_string).collect()),
+                    span,
+                });
+            }
+        }
+    }
+
+
     /// Flush changes.
     ///
     /// This ought to be called after sections have been completed and in the end of the file.
@@ -286,10 +1212,9 @@ impl Parser {
         match mem::replace(&mut self.state, ParserState::Flushed) {
             // The global settings are written directly to the deck; nothing to flush.
             ParserState::GlobalSettings => (),
-            // TODO: Get rid of the hack `tag != ""` and detect overwritten settings for the
-            //       default tag as well. It is important to use `&` and not `&&` to avoid
-            //       short-circuiting.
-            // Insert the new tag settings.
+            // Insert the new tag settings. The default settings may be given several times, so only
+            // the other tags are checked for duplicates (using `&` rather than `&&`, such that the
+            // settings are inserted either way).
             ParserState::TagSettings(tag) => if (tag != "") & self.deck.tag_settings
                 // Swap the current settings with the default settings.
                 .insert(tag, mem::replace(&mut self.current_tag_settings, self.deck.default_tag_settings()))
@@ -309,6 +1234,8 @@ impl Parser {
                         // Add the path to the list of files.
                         card.view.push(cards::View::Pdf(format!("{}.pdf", id)));
                     }
+                    // Remember the order of the cards.
+                    card.index = self.deck.cards.len();
 
                     card
                 })
@@ -316,17 +1243,17 @@ impl Parser {
                     // Throw an error if the card already exists in the deck.
                     return Err(ParsingError::Other("the same card ID appears multiple times"));
                 },
-            // Already flushed; do nothing.
-            ParserState::Flushed => (),
+            // Already flushed or nothing to flush; do nothing.
+            ParserState::Flushed | ParserState::Skipped => (),
         }
 
         Ok(())
     }
 
     /// Parse a single line and update state accordingly.
-    fn parse_line(&mut self, mut line: &str) -> Result<(), ParsingError> {
+    fn parse_line(&mut self, raw: &str) -> Result<(), ParsingError> {
         // Canonicalize lines.
-        line = line.trim();
+        let line = raw.trim();
         if line.is_empty() {
             return Ok(());
         }
@@ -334,13 +1261,39 @@ impl Parser {
         // Skip comments.
         if line.starts_with('#') {
             return Ok(());
+        // Handle include directives, which close off the section like a new one.
+        } else if let Some(path) = included(line) {
+            self.flush()?;
+            return self.include(path);
         // Handle new section.
         } else if line.starts_with('[') && line.ends_with(']') {
             // Close off existing section.
             self.flush()?;
 
+            // Check that tags and cards are not configured multiple times. The default tag
+            // settings may be overridden.
+            let title = line[1..line.len() - 1].trim();
+            if (title.starts_with("tag ") && title != "tag default") || title.starts_with("card ") {
+                if let Some(first) = self.sections.get(title) {
+                    // Skip the section.
+                    self.state = ParserState::Skipped;
+                    return Err(ParsingError::DuplicateSection {
+                        title: title.to_string(),
+                        first_line: first.line,
+                        first_file: if first.file == self.file {
+                            None
+                        } else {
+                            Some(self.diagnostics.files[first.file].path.clone())
+                        },
+                    });
+                }
+                // The line has been trimmed, so it starts at the first non-whitespace character.
+                let column = raw.len() - raw.trim_start().len();
+                self.sections.insert(title.to_string(), Span::new(self.file, self.line_num, raw, column, line));
+            }
+
             // Update the state.
-            self.state = match line[1..line.len() - 1].trim() {
+            self.state = match title {
                 // Global settings.
                 "settings" => ParserState::GlobalSettings,
                 // Default tag settings.
@@ -359,7 +1312,11 @@ impl Parser {
                         // name.
                         title["card ".len()..].trim_start().to_string()
                     ),
-                _ => return Err(ParsingError::Other("unknown section")),
+                _ => {
+                    // Skip the section.
+                    self.state = ParserState::Skipped;
+                    return Err(ParsingError::UnknownSection(title.to_string()));
+                },
             };
 
             return Ok(());
@@ -375,12 +1332,18 @@ impl Parser {
                     "max new queue" => self.deck.settings.max_new_queue = value.parse()?,
                     "max new daily" => self.deck.settings.max_new_daily = value.parse()?,
                     "min new probability" => self.deck.settings.min_new_probability = value.parse()?,
-                    _ => return Err(ParsingError::Other("unknown key")),
+                    "new card order" => self.deck.settings.new_card_order = settings::NewCardOrder::from_name(value)
+                        .ok_or(ParsingError::Other("invalid new card order; must be deck, priority, random or tag"))?,
+                    _ => return Err(unknown_key(key, GLOBAL_KEYS)),
                 }
             },
             ParserState::TagSettings(..) => {
                 // Read key-value pair.
                 let (key, value) = key_value(line)?;
+                // Remember inherited tags, as their settings are used even if no card has them.
+                if key == "INHERIT" {
+                    self.inherited.insert(value.to_string());
+                }
 
                 // Update tag settings.
                 match key {
@@ -388,41 +1351,10 @@ impl Parser {
                         .get(value)
                         .ok_or(ParsingError::Other("cannot inherit nonexistent settings"))?
                         .clone(),
-                    "learning intervals"
-                        => self.current_tag_settings.learning_intervals = parse_list(value, parse_duration)?,
-                    "learning interval progressions"
-                        => self.current_tag_settings.learning_interval_progressions = to_score_array(parse_list(value, str::parse)?)?,
-                    "relearning intervals"
-                        => self.current_tag_settings.relearning_intervals = parse_list(value, parse_duration)?,
-                    "relearning interval progressions"
-                        => self.current_tag_settings.relearning_interval_progressions = to_score_array(parse_list(value, str::parse)?)?,
-                    "max interval" => self.current_tag_settings.max_interval = parse_duration(value)?,
-                    "min interval increase"
-                        => self.current_tag_settings.min_interval_increase = parse_duration(value)?,
-                    "starting ease" => self.current_tag_settings.starting_ease = value.parse()?,
-                    "min ease" => self.current_tag_settings.min_ease = value.parse()?,
-                    "max ease" => self.current_tag_settings.max_ease = value.parse()?,
-                    "ease increase"
-                        => self.current_tag_settings.ease_increase = to_score_array(parse_list(value, str::parse)?)?,
-                    "interval modifier" => self.current_tag_settings.interval_modifier = value.parse()?,
-                    "score modifiers"
-                        => self.current_tag_settings.score_modifiers = to_score_array(parse_list(value, str::parse)?)?,
-                    "priority modifiers"
-                        => self.current_tag_settings.priority_modifiers = to_score_array(parse_list(value, str::parse)?)?,
-                    "score weight"
-                        => self.current_tag_settings.score_weight = value.parse()?,
-                    "familiarity delta"
-                        => self.current_tag_settings.familiarity_delta = value.parse()?,
-                    "max familiarity"
-                        => self.current_tag_settings.max_familiarity = value.parse()?,
-                    "min familiarity"
-                        => self.current_tag_settings.min_familiarity = value.parse()?,
-                    "desired retention rate"
-                        => self.current_tag_settings.desired_retention_rate = value.parse()?,
-                    _ => return Err(ParsingError::Other("unknown key")),
+                    _ => set_tag_setting(&mut self.current_tag_settings, key, value)?,
                 }
             },
-            ParserState::Card(..) => {
+            ParserState::Card(ref id) => {
                 // Read key-value pair.
                 let (key, value) = key_value(line)?;
 
@@ -430,8 +1362,12 @@ impl Parser {
                 match key {
                     "pdf" => self.current_card.view.extend(value.split(',').map(|x| x.trim().into()).map(cards::View::Pdf)),
                     "sh" => self.current_card.view.push(cards::View::Command(cards::Command(value.to_string()))),
+                    "sh dir" => self.current_card.command_dir = Some(value.to_string()),
+                    "sh timeout" => self.current_card.command_timeout = Some(parse_duration(value)?),
                     "tags" => self.current_card.tags.extend(value.split(',').map(|x| x.trim().to_string())),
                     "max interval" => self.current_card.max_interval = parse_duration(value)?,
+                    "answer" => self.current_card.answers.push(answer::Answer::text(value)),
+                    "answer regex" => self.current_card.answers.push(answer::Answer::regex(value)?),
                     "priority" => {
                         // Parse the priority
                         let priority = value.parse::<cards::Priority>()? - 1;
@@ -442,11 +1378,51 @@ impl Parser {
                         // Update priority.
                         self.current_card.priority = priority;
                     },
-                    _ => return Err(ParsingError::Other("unknown key")),
+                    "settings" => {
+                        // The settings may be defined later, so they are checked in the end.
+                        let group = if value == "default" { "" } else { value };
+                        self.current_card.settings = Some(group.to_string());
+                        let column = raw.len() - raw.trim_start().len() + line.len() - value.len();
+                        self.groups.push((group.to_string(), Span::new(self.file, self.line_num, raw, column, value)));
+                    },
+                    "requires" => {
+                        // The cards may be defined later, so they are checked in the end.
+                        // The byte in `raw` at which the current part of the list starts.
+                        let mut start = raw.len() - raw.trim_start().len() + line.len() - value.len();
+                        for part in value.split(',') {
+                            let required = part.trim();
+                            if !required.is_empty() {
+                                let span = Span::new(self.file, self.line_num, raw, start + part.len() - part.trim_start().len(), required);
+                                self.current_card.requires.push(required.to_string());
+                                self.prerequisites.push((id.clone(), required.to_string(), span));
+                            }
+                            start += part.len() + 1;
+                        }
+                    },
+                    "position" => self.current_card.position = Some(value.parse()?),
+                    key if key.starts_with(FIELD_PREFIX) => {
+                        let field = key[FIELD_PREFIX.len()..].trim();
+                        if field.is_empty() {
+                            return Err(ParsingError::Other("field without a name"));
+                        }
+                        self.current_card.fields.insert(field.to_string(), value.to_string());
+                    },
+                    key if overridable(key) => {
+                        // Check the value right away, by applying it to some settings.
+                        set_tag_setting(&mut settings::TagSettings::default(), key, value)?;
+                        self.current_card.overrides.push((key.to_string(), value.to_string()));
+                    },
+                    _ => {
+                        // Suggest tag settings as well.
+                        let keys: Vec<_> = CARD_KEYS.iter().chain(TAG_KEYS).cloned().filter(|&key| key != "INHERIT").collect();
+                        return Err(unknown_key(key, &keys));
+                    },
                 }
             },
-            // TODO: Somehow, this is not unreachable. Try to parse a random file.
-            ParserState::Flushed => unreachable!(),
+            // Key-value pairs must belong to a section.
+            ParserState::Flushed => return Err(ParsingError::Other("key-value pair outside of a section")),
+            // Ignore the content of invalid sections.
+            ParserState::Skipped => (),
         }
 
         Ok(())
@@ -507,7 +1483,7 @@ priority: 5
 ";
         let deck = Deck::parse(input).unwrap();
         assert_eq!(
-            chrono::Duration::weeks(4 * 12 * 4),
+            chrono::Duration::seconds(4 * 31_556_952),
             deck.tag_settings["Theorem"].max_interval,
         );
         assert_eq!(deck.cards["123"].tags[0], "Definition");
@@ -520,25 +1496,46 @@ priority: 5
 [card 123]
 tags: Definition, Week 2
 priority: 5
-file: fibration.pdf
+pdf: fibration.pdf
 
 
 
 [card 124]
 tags: Definition
 priority: 5
-file: derived_couple.pdf
+pdf: derived_couple.pdf
 
 [card 125]
 tags: Definition
 priority: 5
-file: triad.pdf
+pdf: triad.pdf
 max interval: 500d
 
 [card 127]
 tags: Definition
 priority: 5
-file: spectral_sequence.pdf
+pdf: spectral_sequence.pdf
+        ").unwrap();
+    }
+
+    #[test]
+    fn answers() {
+        let deck = Deck::parse(r"
+[card yoneda]
+tags: Theorem
+answer: Yoneda lemma
+answer regex: yoneda('s)?( lemma)?
+        ").unwrap();
+        assert_eq!(deck.cards["yoneda"].answers.len(), 2);
+        assert_eq!(deck.cards["yoneda"].answers[0].as_str(), "Yoneda lemma");
+    }
+
+    #[test]
+    #[should_panic]
+    fn error_invalid_regex() {
+        Deck::parse(r"
+[card yoneda]
+answer regex: yoneda(
         ").unwrap();
     }
 
@@ -623,4 +1620,371 @@ file: module.pdf
             desired retention rate: 0.85
         ").unwrap();
     }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("1d 12h").unwrap(), chrono::Duration::hours(36));
+        assert_eq!(parse_duration("1h30m").unwrap(), chrono::Duration::minutes(90));
+        assert_eq!(parse_duration("1.5d").unwrap(), chrono::Duration::hours(36));
+        assert_eq!(parse_duration("10s").unwrap(), chrono::Duration::seconds(10));
+        // Months and years follow the calendar on average.
+        assert_eq!(parse_duration("1y").unwrap(), chrono::Duration::seconds(365 * 86400 + 20952));
+        assert_eq!(parse_duration("12M").unwrap(), parse_duration("1y").unwrap());
+
+        match parse_duration("3d 2q") {
+            Err(ParsingError::UnknownUnit { ref unit, .. }) => assert_eq!(unit, "q"),
+            ref result => panic!("unexpected result {:?}", result),
+        }
+        assert_eq!(parse_duration("3").unwrap_err().to_string(), "missing unit in duration `3` (expected s, m, h, d, w, M or y)");
+        assert!(parse_duration("d").is_err());
+
+        for &duration in &["2M", "36h", "3w 1d 1h 1m 1s", "0m"] {
+            assert_eq!(format_duration(parse_duration(duration).unwrap()), duration);
+        }
+    }
+
+    #[test]
+    fn error_details() {
+        let err = Deck::parse("[card a]\n  priorityy: 5\n").unwrap_err();
+        assert_eq!(err.span(), Span { file: 0, line: 2, column: 3, len: 9 });
+        assert_eq!(err.to_string(), "unknown key `priorityy` (did you mean `priority`?); at line 2, column 3");
+        assert_eq!(err.render("deck.mu", "[card a]\n  priorityy: 5\n"), "\
+unknown key `priorityy` (did you mean `priority`?)
+ --> deck.mu:2:3
+  |
+2 |   priorityy: 5
+  |   ^^^^^^^^^");
+
+        match *Deck::parse("[tag default]\nscore modifiers: 1, 2\n").unwrap_err().error() {
+            ParsingError::WrongLength { expected: 5, actual: 2 } => (),
+            ref err => panic!("unexpected error {:?}", err),
+        }
+        match *Deck::parse("[tag default]\nmax interval: 2q\n").unwrap_err().error() {
+            ParsingError::UnknownUnit { ref unit, .. } => assert_eq!(unit, "q"),
+            ref err => panic!("unexpected error {:?}", err),
+        }
+        match *Deck::parse("[card a]\n[card b]\n[card a]\n").unwrap_err().error() {
+            ParsingError::DuplicateSection { first_line: 1, .. } => (),
+            ref err => panic!("unexpected error {:?}", err),
+        }
+    }
+
+    #[test]
+    fn diagnostics() {
+        let (deck, diagnostics) = Deck::parse_with_diagnostics("
+[tag Unused]
+starting ease: 2
+[tag Base]
+starting ease: 2
+[tag Used]
+INHERIT: Base
+[card a]
+tags: Used
+priorityy: 2
+[card b]
+max interval: 3q
+[sections]
+nonsense
+[card c]
+tags: Used
+");
+        let errors: Vec<_> = diagnostics.errors.iter().map(|err| err.span().line).collect();
+        assert_eq!(errors, [10, 12, 13]);
+        let warnings: Vec<_> = diagnostics.warnings.iter().map(ToString::to_string).collect();
+        assert_eq!(warnings, ["tag `Unused` is configured, but no card has it", "card `b` has no tags"]);
+        // The valid parts are kept.
+        assert_eq!(deck.cards.len(), 3);
+    }
+
+    #[test]
+    fn include() {
+        let dir = std::env::temp_dir().join(format!("mu-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("courses")).unwrap();
+        fs::write(dir.join("deck.mu"), "[include settings.mu]\n[include courses/algebra.mu]\n").unwrap();
+        fs::write(dir.join("settings.mu"), "[tag Algebra]\nstarting ease: 2\n").unwrap();
+        fs::write(dir.join("courses/algebra.mu"), "[card a]\ntags: Algebra\n[include cycle.mu]\n[card a]\n").unwrap();
+        fs::write(dir.join("courses/cycle.mu"), "[include algebra.mu]\n").unwrap();
+
+        let (deck, diagnostics) = Deck::load_with_diagnostics(dir.join("deck.mu")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(deck.tag_settings["Algebra"].starting_ease, 2.0);
+        assert!(deck.cards.contains_key("a"));
+        // The errors refer to the files they are in.
+        let errors: Vec<_> = diagnostics.errors.iter()
+            .map(|err| (diagnostics.file(err.span()).path.strip_prefix(&dir).unwrap(), err.span().line))
+            .collect();
+        assert_eq!(errors, [
+            (path::Path::new("courses/cycle.mu"), 1),
+            (path::Path::new("courses/algebra.mu"), 4),
+        ]);
+        match *diagnostics.errors[0].error() {
+            ParsingError::IncludeCycle(..) => (),
+            ref err => panic!("unexpected error {:?}", err),
+        }
+        // Strings cannot include files.
+        assert_eq!(
+            Deck::parse("[include deck.mu]\n[card a]\n").unwrap_err().error().to_string(),
+            "files can only be included by deck files",
+        );
+    }
+
+    #[test]
+    fn include_twice() {
+        let dir = std::env::temp_dir().join(format!("mu-include-twice-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("deck.mu"), "[include algebra.mu]\n[include analysis.mu]\n").unwrap();
+        fs::write(dir.join("algebra.mu"), "[include common.mu]\n[card a]\ntags: Math\n").unwrap();
+        fs::write(dir.join("analysis.mu"), "[include common.mu]\n[card b]\ntags: Math\n").unwrap();
+        fs::write(dir.join("common.mu"), "[tag Math]\nstarting ease: 2\n").unwrap();
+
+        let (deck, diagnostics) = Deck::load_with_diagnostics(dir.join("deck.mu")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        // The common file is only parsed once, so its section is not duplicated.
+        assert!(diagnostics.errors.is_empty());
+        assert_eq!(deck.cards.len(), 2);
+        assert_eq!(deck.tag_settings["Math"].starting_ease, 2.0);
+    }
+
+    #[test]
+    fn to_mu_string() {
+        let deck = Deck::parse(r"
+[card b]
+tags: Theorem, Algebra
+answer regex: (a|b)+
+priority: 4
+[tag Theorem]
+max interval: 2M
+starting ease: 2
+min ease: 1
+[tag Algebra]
+INHERIT: Theorem
+[tag default]
+starting ease: 2
+[settings]
+max new daily: 5
+[card a]
+pdf: x.pdf, y.pdf
+sh timeout: 90m
+").unwrap();
+        assert_eq!(deck.to_mu_string(), "\
+[settings]
+max new daily: 5
+
+[tag default]
+starting ease: 2
+
+[tag Algebra]
+max interval: 2M
+starting ease: 2
+min ease: 1
+
+[tag Theorem]
+INHERIT: Algebra
+
+[card b]
+tags: Theorem, Algebra
+answer regex: (a|b)+
+priority: 4
+
+[card a]
+pdf: x.pdf
+pdf: y.pdf
+sh timeout: 90m
+");
+    }
+
+    #[test]
+    fn hierarchical_tags() {
+        let (deck, diagnostics) = Deck::parse_with_diagnostics("
+[tag Math]
+starting ease: 2
+[tag Math::Algebra]
+starting ease: 3
+[card a]
+tags: Math::Algebra::Groups, Math::Analysis
+[card b]
+tags: Physics, Math::Analysis
+[card c]
+tags: Physics
+");
+        assert_eq!(deck.settings_tag(&deck.cards["a"].tags), "Math::Algebra");
+        assert_eq!(deck.settings_tag(&deck.cards["b"].tags), "Math");
+        assert_eq!(deck.settings_tag(&deck.cards["c"].tags), "");
+        assert_eq!(deck.tag_settings(&deck.cards["a"].tags).starting_ease, 3.0);
+        // Tags used by descendants are not unused.
+        assert!(diagnostics.warnings.is_empty());
+
+        assert_eq!(deck.cards["a"].tags_with_ancestors(), [
+            "Math::Algebra::Groups",
+            "Math::Algebra",
+            "Math",
+            "Math::Analysis",
+        ]);
+        assert!(cards::tag_matches("Math::Algebra", "Math"));
+        assert!(!cards::tag_matches("Mathematics", "Math"));
+    }
+
+    #[test]
+    fn card_settings() {
+        let (deck, diagnostics) = Deck::parse_with_diagnostics("
+[card a]
+tags: Math::Algebra, Exercise
+[card b]
+tags: Math::Algebra
+settings: Exercise
+starting ease: 1.5
+learning intervals: 1d, 1w
+[card c]
+tags: Math::Algebra
+settings: default
+[card d]
+settings: Nonexistent
+starting ease: x
+[tag Math]
+starting ease: 2
+[tag Exercise]
+starting ease: 3
+max ease: 4
+");
+        // The most specific tag wins over the order of the tags.
+        assert_eq!(deck.settings_group(&deck.cards["a"]), "Math");
+        // Explicit settings win over tags, and overridden keys over both.
+        assert_eq!(deck.settings_group(&deck.cards["b"]), "Exercise");
+        let settings = deck.card_settings(&deck.cards["b"]);
+        assert_eq!(settings.starting_ease, 1.5);
+        assert_eq!(settings.learning_intervals, [chrono::Duration::days(1), chrono::Duration::weeks(1)]);
+        assert_eq!(settings.max_ease, 4.0);
+        assert_eq!(deck.settings_group(&deck.cards["c"]), "");
+
+        let errors: Vec<_> = diagnostics.errors.iter().map(|err| (err.span().line, err.to_string())).collect();
+        assert_eq!(errors, [
+            (14, "failed to parse float (invalid float literal); at line 14, column 16".to_string()),
+            (13, "there are no settings for tag `Nonexistent`; at line 13, column 11".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn fields() {
+        let deck = Deck::parse("
+[card a]
+tags: Topology
+field source: Hatcher p. 42
+field  see also : Seifert-van Kampen theorem
+").unwrap();
+        assert_eq!(deck.cards["a"].fields["source"], "Hatcher p. 42");
+        assert_eq!(deck.cards["a"].fields["see also"], "Seifert-van Kampen theorem");
+        assert_eq!(deck.cards["a"].fields.len(), 2);
+        assert_eq!(cards::field_variable("source page"), "CARD_FIELD_SOURCE_PAGE");
+        // Undeclared keys are errors, whether or not they look like a known key.
+        for line in &["priorityy: 2", "prioirty_level: 2", "tgas: Topology", "source: Hatcher", "field : x"] {
+            assert!(Deck::parse(&format!("[card a]\n{}\n", line)).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn prerequisites() {
+        let (deck, diagnostics) = Deck::parse_with_diagnostics("
+[card a]
+requires: b, c
+[card b]
+requires: d
+[card c]
+[card d]
+requires: a, f,
+[card e]
+requires: e
+");
+        assert_eq!(deck.cards["a"].requires, ["b", "c"]);
+        let errors: Vec<_> = diagnostics.errors.iter()
+            .map(|err| (err.span().line, err.span().column, err.error().to_string()))
+            .collect();
+        assert_eq!(errors, [
+            (8, 14, "there is no card `f`".to_string()),
+            (8, 11, "cyclic prerequisites: `d` requires `a`, which requires `b`, which requires `d`".to_string()),
+            (10, 11, "cyclic prerequisites: `e` requires `e`".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn round_trip() {
+        use rand::{seq::SliceRandom, Rng, SeedableRng};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let mut deck = Deck::default();
+            deck.settings.max_new_daily = rng.gen_range(0, 20);
+            deck.settings.new_card_order = settings::NewCardOrder::from_name(settings::NewCardOrder::NAMES[rng.gen_range(0, 4)]).unwrap();
+            // Give some tags random settings, starting from those of other tags.
+            for n in 0..rng.gen_range(0, 5) {
+                let base = format!("T{}", rng.gen_range(0, 5));
+                let mut settings = deck.tag_settings.get(&base).cloned().unwrap_or_default();
+                if rng.gen() {
+                    settings.starting_ease = rng.gen_range(10, 40) as f32 / 10.0;
+                }
+                if rng.gen() {
+                    settings.max_interval = chrono::Duration::minutes(rng.gen_range(1, 1_000_000));
+                }
+                if rng.gen() {
+                    settings.learning_intervals = (0..rng.gen_range(1, 4))
+                        .map(|_| chrono::Duration::days(rng.gen_range(1, 100)))
+                        .collect();
+                }
+                if rng.gen() {
+                    settings.score_modifiers[rng.gen_range(0, cards::SCORES)] = rng.gen::<f32>();
+                }
+                let tag = if n == 0 { String::new() } else { format!("T{}", n) };
+                deck.tag_settings.insert(tag, settings);
+            }
+            // Add cards with random metadata, in a random order.
+            let mut indices: Vec<_> = (0..rng.gen_range(1, 5)).collect();
+            indices.shuffle(&mut rng);
+            for (n, &index) in indices.iter().enumerate() {
+                let mut card = cards::Card {
+                    index,
+                    priority: rng.gen_range(0, 5),
+                    tags: (0..rng.gen_range(0, 3)).map(|n| format!("T{}", n)).collect(),
+                    ..Default::default()
+                };
+                if rng.gen() {
+                    card.view.push(cards::View::Command(cards::Command("echo hi".to_string())));
+                }
+                if rng.gen() {
+                    card.view.push(cards::View::Pdf(format!("c{}.pdf", n)));
+                }
+                if rng.gen() {
+                    card.answers.push(answer::Answer::regex("a+|b").unwrap());
+                }
+                if rng.gen() {
+                    card.settings = Some(String::new());
+                }
+                if rng.gen() {
+                    card.overrides.push(("starting ease".to_string(), "1.5".to_string()));
+                }
+                if rng.gen() {
+                    card.fields.insert("source".to_string(), format!("Hatcher p. {}", rng.gen_range(1, 500)));
+                }
+                if rng.gen() {
+                    card.position = Some(rng.gen_range(-10, 10));
+                }
+                // Only require earlier cards, such that there are no cycles.
+                if n > 0 && rng.gen() {
+                    card.requires.push(format!("c{}", rng.gen_range(0, n)));
+                }
+                deck.cards.insert(format!("c{}", n), card);
+            }
+
+            // Parsing the written deck gives the same deck, which in turn is written the same way.
+            let written = deck.to_mu_string();
+            let parsed = Deck::parse(&written).unwrap_or_else(|err| panic!("{}\n{}", err, written));
+            assert_eq!(parsed.to_mu_string(), written);
+            for (tag, settings) in &deck.tag_settings {
+                assert_eq!(tag_setting_values(&parsed.tag_settings[tag]), tag_setting_values(settings));
+            }
+            for (id, card) in &deck.cards {
+                assert_eq!(card_lines(id, &parsed.cards[id]), card_lines(id, card));
+                // The order of the cards is kept.
+                assert_eq!(parsed.cards[id].index, card.index);
+            }
+        }
+    }
 }
@@ -8,16 +8,19 @@
 
 extern crate chrono;
 extern crate rand;
+extern crate regex;
 extern crate serde_yaml as yaml;
 
 mod settings;
 mod deck;
 mod cards;
 mod scheduler;
+mod answer;
 
-pub use deck::Deck;
-pub use cards::{Card, CardState, Score, Metacard, Review, Command, View, SCORES};
+pub use deck::{included, Deck, DURATION_UNITS, Diagnostics, ParsingError, ParsingErrorLine, SourceFile, Span, Warning};
+pub use cards::{field_variable, tag_ancestors, tag_matches, Card, CardState, Score, Metacard, Review, Command, CommandOutcome, View, SCORES, TAG_SEPARATOR};
 pub use scheduler::{Schedule, Scheduler, Statistics};
+pub use answer::{Answer, DiffChunk, Grade};
 
 /// A point in time.
 type Time = chrono::DateTime<chrono::Utc>;
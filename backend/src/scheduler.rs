@@ -1,7 +1,7 @@
 //! Scheduling of flashcards.
 
 use std::cmp;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
 use rand::Rng;
 use serde::{Serialize, Deserialize};
@@ -93,6 +93,45 @@ impl Statistics {
     }
 }
 
+/// Get the sort key of the card `id` in the random order with seed `seed`.
+///
+/// This is a hash of the ID, such that adding or removing cards does not reorder the others.
+fn random_key(seed: u64, id: &str) -> u64 {
+    // FNV-1a, starting from the seed.
+    let mut hash = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for byte in id.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100_0000_01b3);
+    }
+    // Mix the bits, since the hashes of similar IDs are close.
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^ (hash >> 33)
+}
+
+/// Compare the cards with IDs `a` and `b` of `deck` by the order in which they are introduced.
+///
+/// `seed` is the seed of the random order.
+fn compare_new_cards(deck: &deck::Deck, seed: u64, a: &str, b: &str) -> cmp::Ordering {
+    let (card_a, card_b) = (&deck.cards[a], &deck.cards[b]);
+    // Compare by the chosen order.
+    let order = match deck.settings.new_card_order {
+        settings::NewCardOrder::Deck => cmp::Ordering::Equal,
+        settings::NewCardOrder::Priority => card_b.priority.cmp(&card_a.priority),
+        settings::NewCardOrder::Random => random_key(seed, a).cmp(&random_key(seed, b)),
+        // Cards without tags come last.
+        settings::NewCardOrder::Tag => (card_a.tags.is_empty(), card_a.tags.first())
+            .cmp(&(card_b.tags.is_empty(), card_b.tags.first())),
+    };
+
+    // Cards with positions come first.
+    (card_a.position.is_none(), card_a.position).cmp(&(card_b.position.is_none(), card_b.position))
+        .then(order)
+        // Break ties by the order of the deck, and then by ID for cards that are not from a file.
+        .then(card_a.index.cmp(&card_b.index))
+        .then(a.cmp(b))
+}
+
 /// A card schedule.
 ///
 /// Card schedules are the persistently stored state data of Mu. They contain a number of so-called
@@ -118,6 +157,18 @@ pub struct Schedule {
     /// `settings.max_new_daily`.
     #[serde(default)]
     new_today: u32,
+    /// Number of new cards that could not be introduced today, as their prerequisites were not
+    /// learnt yet.
+    ///
+    /// As many cards are introduced later the same day, when the prerequisites of some cards become
+    /// learnt.
+    #[serde(default)]
+    held_back: u32,
+    /// The seed of the random order of new cards.
+    ///
+    /// This is chosen when the order is first used, such that it stays the same across sessions.
+    #[serde(default)]
+    seed: Option<u64>,
 }
 
 impl Schedule {
@@ -130,6 +181,8 @@ impl Schedule {
             // We start with `None`, which tells `update()` to fill new queue when called.
             updated: None,
             new_today: 0,
+            held_back: 0,
+            seed: None,
         }
     }
 
@@ -151,6 +204,8 @@ impl Schedule {
     }
 
     /// Get the tag-specified statistics.
+    ///
+    /// The statistics of a tag include the reviews of cards with its descendants.
     pub fn tag_statistics(&self) -> &HashMap<String, Statistics> {
         &self.tag_statistics
     }
@@ -178,12 +233,12 @@ pub struct Scheduler {
     ///
     /// It is assumed that either this or `Scheduler::new_queue` is nonempty.
     queue: BTreeMap<Time, MetacardRef>,
-    /// New cards that have not been scheduled yet.
+    /// New cards that have not been scheduled yet, in the order they are introduced.
     new_cards: Vec<MetacardRef>,
-    /// New cards that are scheduled to be reviewed.
+    /// New cards that are scheduled to be reviewed, in the order they were introduced.
     ///
     /// It is assumed that either this or `Scheduler::queue` is nonempty.
-    new_queue: Vec<MetacardRef>,
+    new_queue: VecDeque<MetacardRef>,
     /// The number of cards that is due.
     ///
     /// This is supposed to reflect the number of cards in `self.queue` whose scheduled time is
@@ -232,20 +287,29 @@ impl Scheduler {
             //       `&str`.
         }
 
-        // De-orphan cards that are not a part of the scheduler yet.
-        for (id, card) in deck.cards.iter().filter(|(id, _)| !queued_cards.contains(id.as_str())) {
+        // De-orphan cards that are not a part of the scheduler yet, in the order of the deck.
+        let mut orphans: Vec<_> = deck.cards.iter().filter(|(id, _)| !queued_cards.contains(id.as_str())).collect();
+        orphans.sort_by_key(|&(id, card)| (card.index, id));
+        for (id, card) in orphans {
             // Add the orphaned card to the schedule.
-            sched.metacards.push(cards::Metacard::new(id.clone(), deck.tag_settings(&card.tags)));
+            sched.metacards.push(cards::Metacard::new(id.clone(), &deck.card_settings(card)));
             // Add the it to the new queue.
             new_cards.push(sched.metacards.len() - 1);
         }
 
+        // Order the new cards, starting with the first to be introduced.
+        let seed = match deck.settings.new_card_order {
+            settings::NewCardOrder::Random => *sched.seed.get_or_insert_with(rand::random),
+            _ => 0,
+        };
+        new_cards.sort_by(|&a, &b| compare_new_cards(&deck, seed, &sched.metacards[a].id, &sched.metacards[b].id));
+
         // Start with empty state.
         let mut sched = Scheduler {
             sched,
             deck,
             new_cards,
-            new_queue: Vec::new(),
+            new_queue: VecDeque::new(),
             // Have a nonsense value, such that in case of any bugs, we should get an OOB.
             current_card: !0,
             queue,
@@ -254,18 +318,16 @@ impl Scheduler {
         };
 
         // Populate the new cards queue with the new cards from today, that are not studied yet.
-        for _ in 0..sched.sched.new_today {
-            if let Some(card) = sched.new_cards.pop() {
-                sched.new_queue.push(card);
-            } else { break; }
-        }
+        let new_today = sched.sched.new_today as usize;
+        sched.release_new_cards(new_today);
 
         // Generate the rest of the scheduler.
         sched.update();
         // If the queues are still empty, forcibly add a new card to the new queue.
         if sched.new_queue.is_empty() && sched.queue.is_empty() {
-            // We can safely unwrap, since `deck` was assumed nonempty.
-            sched.new_queue.push(sched.new_cards.pop().unwrap());
+            // All the cards are new, since the others are queued. As `deck` was assumed nonempty
+            // and the prerequisites cannot form cycles, some of them can be released.
+            sched.release_new_cards(1);
         }
         // Pick a card.
         sched.pick_card();
@@ -289,20 +351,46 @@ impl Scheduler {
             self.sched.updated = Some(now);
             // Populate the new cards queue. Push new cards until the daily cap or the maximal
             // length is achieved.
-            for _ in self.new_queue.len()..cmp::min(
+            let count = cmp::min(
                 self.deck.settings.max_new_daily + self.new_queue.len(),
                 self.deck.settings.max_new_queue
-            ) {
-                if let Some(card) = self.new_cards.pop() {
-                    self.new_queue.push(card);
-                } else { break; }
-            }
+            ).saturating_sub(self.new_queue.len());
+            // If fewer cards than allowed were released, the remaining new cards must all wait for
+            // their prerequisites.
+            let released = self.release_new_cards(count);
+            self.sched.held_back = cmp::min(count - released, self.new_cards.len()) as u32;
         }
 
         // Set the number of new cards for today.
         self.sched.new_today = self.new_queue.len() as u32;
     }
 
+    /// Move up to `count` cards from the new cards to the new queue, and return how many were moved.
+    ///
+    /// Cards are only released once all their prerequisites are learnt; the others stay new.
+    fn release_new_cards(&mut self, count: usize) -> usize {
+        // The cards that are learnt.
+        let learnt: HashSet<&str> = self.sched.metacards
+            .iter()
+            .filter(|metacard| metacard.state == cards::CardState::Learnt)
+            .map(|metacard| metacard.id.as_str())
+            .collect();
+        // Take the cards in order, skipping those that must wait.
+        let mut released = 0;
+        let mut i = 0;
+        while released < count && i < self.new_cards.len() {
+            let card = &self.deck.cards[&self.sched.metacards[self.new_cards[i]].id];
+            if card.requires.iter().all(|id| learnt.contains(id.as_str())) {
+                self.new_queue.push_back(self.new_cards.remove(i));
+                released += 1;
+            } else {
+                i += 1;
+            }
+        }
+
+        released
+    }
+
     /// Reschedule the current card.
     fn reschedule(&mut self) {
         // Get current time.
@@ -344,11 +432,10 @@ impl Scheduler {
         };
 
         if new {
-            // TODO: It is likely better to pop from the other end such that the earliest
-            //       introduced cards gets into play even if the new queue piles up.
-            // Simply pop from the new queue (possibly yielding `None`). We can safely unwrap here,
-            // since `new` is false when `self.new_queue` is empty.
-            self.current_card = self.new_queue.pop().unwrap();
+            // Take the earliest introduced card, such that it gets into play even if the new queue
+            // piles up. We can safely unwrap here, since `new` is false when `self.new_queue` is
+            // empty.
+            self.current_card = self.new_queue.pop_front().unwrap();
         } else {
             // Take out the next card from the queue. We can safely unwrap, since it is assumed
             // that `self.queue` is nonempty.
@@ -368,7 +455,7 @@ impl Scheduler {
         // let card_id = &self.sched.metacards[self.current_card].id;
 
         // Update the card.
-        let tag_settings = self.deck.tag_settings(&self.deck.cards[&self.sched.metacards[self.current_card].id].tags);
+        let tag_settings = self.deck.card_settings(&self.deck.cards[&self.sched.metacards[self.current_card].id]);
 
         // The sum of the familiarities. Start with the global familiarity.
         let mut familiarity_sum = self.sched.statistics.familiarity;
@@ -377,8 +464,8 @@ impl Scheduler {
 
         // Update statistics.
         self.sched.statistics.review(score, &tag_settings);
-        // Update tagwise statistics.
-        let tags = &self.deck.cards[&self.sched.metacards[self.current_card].id].tags;
+        // Update tagwise statistics. The reviews count for the ancestors of the tags as well.
+        let tags = self.deck.cards[&self.sched.metacards[self.current_card].id].tags_with_ancestors();
         for tag in tags {
             if let Some(stat) = self.sched.tag_statistics.get_mut(tag) {
                 // Update the sum.
@@ -390,7 +477,7 @@ impl Scheduler {
                 // Create a new statistics tracker for the tag if it does not already exist.
                 let mut stat = Statistics::new(&tag_settings);
                 stat.review(score, &tag_settings);
-                self.sched.tag_statistics.insert(tag.clone(), stat);
+                self.sched.tag_statistics.insert(tag.to_string(), stat);
             }
         }
 
@@ -400,15 +487,23 @@ impl Scheduler {
         // TODO: Get rid of spaghetti.
         let priority = self.deck.cards[&self.sched.metacards[self.current_card].id].priority;
         let max_interval = self.deck.cards[&self.sched.metacards[self.current_card].id].max_interval;
+        let was_learnt = self.sched.metacards[self.current_card].state == cards::CardState::Learnt;
         self.sched.metacards[self.current_card]
             .review(
-                tag_settings,
+                &tag_settings,
                 score,
                 priority,
                 average_familiarity,
                 max_interval,
             );
 
+        // If the card became learnt, the cards requiring it may be introduced now, in place of
+        // those held back by the last update.
+        if !was_learnt && self.sched.metacards[self.current_card].state == cards::CardState::Learnt {
+            let released = self.release_new_cards(self.sched.held_back as usize);
+            self.sched.held_back -= released as u32;
+        }
+
         // Add back the card to the schedule.
         self.reschedule();
 
@@ -455,7 +550,7 @@ impl Scheduler {
         // The number of tags with familiarity statistics.
         let mut familiarity_num = 1;
         // Calculate the sum, in order to obtain average.
-        for tag in &card.tags {
+        for tag in card.tags_with_ancestors() {
             if let Some(stat) = self.sched.tag_statistics.get(tag) {
                 familiarity_sum += stat.familiarity;
                 familiarity_num += 1;
@@ -465,7 +560,7 @@ impl Scheduler {
         // Calculate the new intervals.
         self.current_metacard()
             .new_intervals(
-                self.deck.tag_settings(&card.tags), card.priority,
+                &self.deck.card_settings(card), card.priority,
                 familiarity_sum / familiarity_num as f32,
                 card.max_interval,
             )
@@ -490,4 +585,88 @@ impl Scheduler {
     pub fn schedule(&self) -> &Schedule {
         &self.sched
     }
+
+    /// Get the deck.
+    pub fn deck(&self) -> &deck::Deck {
+        &self.deck
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tag_statistics_roll_up() {
+        let deck = deck::Deck::parse("
+[card a]
+tags: Math::Algebra::Groups, Math::Analysis
+").unwrap();
+        let mut scheduler = Scheduler::new(deck, Schedule::new(&settings::TagSettings::default()));
+        scheduler.review(cards::Score::Good);
+
+        let mut tags: Vec<_> = scheduler.schedule().tag_statistics().keys().collect();
+        tags.sort();
+        assert_eq!(tags, ["Math", "Math::Algebra", "Math::Algebra::Groups", "Math::Analysis"]);
+        assert_eq!(scheduler.schedule().tag_statistics()["Math"].reviews.len(), 1);
+    }
+
+    #[test]
+    fn prerequisites_hold_back_cards() {
+        let deck = deck::Deck::parse("
+[card a]
+requires: b
+[card b]
+").unwrap();
+        let mut scheduler = Scheduler::new(deck, Schedule::new(&settings::TagSettings::default()));
+        // Only `b` is introduced.
+        assert_eq!(scheduler.current_metacard().id, "b");
+        assert_eq!(scheduler.new_cards(), 0);
+
+        assert_eq!(scheduler.sched.held_back, 1);
+
+        // Once `b` is learnt, `a` is introduced right away, rather than on the next day.
+        let b = scheduler.current_card;
+        while scheduler.sched.metacards[b].state != cards::CardState::Learnt {
+            assert_eq!(scheduler.current_card, b);
+            scheduler.review(cards::Score::Easy);
+        }
+        assert_eq!(scheduler.current_metacard().id, "a");
+        assert_eq!(scheduler.sched.held_back, 0);
+    }
+
+    #[test]
+    fn new_card_order() {
+        // Get the IDs of the cards in the order they are introduced.
+        let order = |settings: &str, schedule: Schedule| {
+            let deck = deck::Deck::parse(&format!("
+[settings]
+{}
+[card c]
+[card a]
+priority: 5
+[card b]
+position: 1
+[card d]
+tags: T
+", settings)).unwrap();
+            let scheduler = Scheduler::new(deck, schedule);
+            // The current card is the first one.
+            let order: Vec<_> = Some(scheduler.current_card).into_iter()
+                .chain(scheduler.new_queue.iter().cloned())
+                .map(|card| scheduler.sched.metacards[card].id.clone())
+                .collect();
+            (order, scheduler.sched.seed)
+        };
+        let schedule = || Schedule::new(&settings::TagSettings::default());
+
+        assert_eq!(order("new card order: deck", schedule()).0, ["b", "c", "a", "d"]);
+        assert_eq!(order("new card order: priority", schedule()).0, ["b", "a", "c", "d"]);
+        assert_eq!(order("new card order: tag", schedule()).0, ["b", "d", "c", "a"]);
+        // The random order is stored, and the same for the same seed.
+        let (random, seed) = order("new card order: random", schedule());
+        assert!(seed.is_some());
+        assert_eq!(random[0], "b");
+        assert_eq!(order("new card order: random", Schedule { seed, ..schedule() }).0, random);
+    }
 }
@@ -1,7 +1,48 @@
 //! User customization and settings.
 
+use std::fmt;
+
 use crate::cards;
 
+/// The order in which new cards are introduced.
+///
+/// Cards with a `position` come first in any case, in order of their positions. Ties are broken by
+/// the order of the deck file and then by ID, so the order is always deterministic.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum NewCardOrder {
+    /// The order of the cards in the deck file.
+    Deck,
+    /// Higher priorities first.
+    Priority,
+    /// A random order, which is fixed by a seed stored in the schedule.
+    Random,
+    /// Alphabetically by the first tag of the cards, such that cards with the same tag are
+    /// introduced together.
+    Tag,
+}
+
+impl NewCardOrder {
+    /// The names of the orders in the `.mu` format.
+    pub const NAMES: [&'static str; 4] = ["deck", "priority", "random", "tag"];
+
+    /// Get the order named `name` in the `.mu` format.
+    pub fn from_name(name: &str) -> Option<NewCardOrder> {
+        match name {
+            "deck" => Some(NewCardOrder::Deck),
+            "priority" => Some(NewCardOrder::Priority),
+            "random" => Some(NewCardOrder::Random),
+            "tag" => Some(NewCardOrder::Tag),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for NewCardOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(NewCardOrder::NAMES[*self as usize])
+    }
+}
+
 /// Global settings.
 #[derive(Debug)]
 pub struct GlobalSettings {
@@ -11,6 +52,8 @@ pub struct GlobalSettings {
     pub max_new_daily: usize,
     /// The lower bound for the probability that a card is drawn from the new queue if nonempty.
     pub min_new_probability: f64,
+    /// The order in which new cards are introduced.
+    pub new_card_order: NewCardOrder,
 }
 
 impl Default for GlobalSettings {
@@ -19,6 +62,7 @@ impl Default for GlobalSettings {
             max_new_queue: 20,
             max_new_daily: 10,
             min_new_probability: 0.15,
+            new_card_order: NewCardOrder::Deck,
         }
     }
 }
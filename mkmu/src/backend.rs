@@ -0,0 +1,297 @@
+//! Backends compiling the different kinds of sources.
+//!
+//! The backend of a source is determined by its extension. TeX (`.tex`), Markdown (`.md`) and Typst
+//! (`.typ`) sources are built in, and further backends (or replacements of the built-in ones) can
+//! be configured in `mkmu.mu` in the source root:
+//!
+//! ```text
+//! [backend org]
+//! command: emacs --batch "$1" -f org-latex-export-to-pdf && mv "${1%.org}.pdf" "$2"
+//! comment: #+
+//! ```
+//!
+//! The command is run by `sh`, with the source as `$1` and the path of the PDF as `$2`.
+//!
+//! Executable files without a backend can be scripts, which are not compiled but copied into the
+//! deck and run by mu as `sh:` views. Their metadata is in `#` comments following the shebang line,
+//! which must include `# mu: card` (see `SCRIPT_MARKER`), such that other executables are skipped.
+
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::{fs, io, path};
+
+use failure::Error;
+
+/// The name of the configuration file in the source root.
+pub const CONFIG_FILE: &str = "mkmu.mu";
+
+/// The line in the metadata header of scripts that marks them as cards.
+pub const SCRIPT_MARKER: &str = "mu: card";
+
+/// Check if the executable with content `content` is a script with the marker `SCRIPT_MARKER`.
+fn is_script(content: &str) -> bool {
+    content.lines()
+        // Skip the shebang line.
+        .skip_while(|line| line.starts_with("#!"))
+        .map_while(|line| line.strip_prefix('#'))
+        .any(|line| line.trim() == SCRIPT_MARKER)
+}
+
+/// The comments holding the metadata header of a source.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Comment {
+    /// Comments starting with some string, such as `%` or `//`.
+    Line(String),
+    /// HTML comments (`<!-- ... -->`), each on a single line.
+    Html,
+}
+
+impl Comment {
+    /// Get the content of the comment `line`, or `None` if it is not a comment.
+    pub fn strip<'a>(&self, line: &'a str) -> Option<&'a str> {
+        match *self {
+            Comment::Line(ref start) => line.strip_prefix(start.as_str()),
+            Comment::Html => line.trim_end().strip_prefix("<!--")?.strip_suffix("-->"),
+        }
+    }
+}
+
+/// How a backend compiles sources.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Compiler {
+    /// latexmk.
+    Latexmk,
+    /// pandoc.
+    Pandoc,
+    /// typst.
+    Typst,
+    /// A shell command, which gets the source as `$1` and the path of the PDF as `$2`.
+    Custom(String),
+    /// Nothing, as the source is a script run by mu.
+    Script,
+}
+
+/// A kind of sources.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backend {
+    /// The comments holding the metadata header.
+    pub comment: Comment,
+    /// The compiler.
+    pub compiler: Compiler,
+}
+
+impl Backend {
+    /// The backend of scripts.
+    pub fn script() -> Backend {
+        Backend {
+            comment: Comment::Line("#".to_string()),
+            compiler: Compiler::Script,
+        }
+    }
+
+    /// Can the sources be card bodies, which are wrapped into templates?
+    pub fn templated(&self) -> bool {
+        self.compiler == Compiler::Latexmk
+    }
+
+    /// Get the command line compiling `input` into `pdf`, which is inside `output_dir`.
+    ///
+    /// Scripts are not compiled, so this must not be called for them.
+    pub fn command(&self, input: &path::Path, output_dir: &path::Path, pdf: &path::Path) -> Vec<String> {
+        let input = input.display().to_string();
+        let pdf = pdf.display().to_string();
+        match self.compiler {
+            // Compile files into deck directory.
+            Compiler::Latexmk => vec!["latexmk".to_string(), format!("-outdir={}", output_dir.display()), input],
+            Compiler::Pandoc => vec!["pandoc".to_string(), input, "-o".to_string(), pdf],
+            Compiler::Typst => vec!["typst".to_string(), "compile".to_string(), input, pdf],
+            // `sh -c` takes the name of the script followed by its arguments.
+            Compiler::Custom(ref command) => vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                command.clone(),
+                "mkmu".to_string(),
+                input,
+                pdf,
+            ],
+            Compiler::Script => unreachable!("scripts are not compiled"),
+        }
+    }
+}
+
+/// The backends, by extension.
+pub struct Backends {
+    /// The backends of each extension.
+    backends: HashMap<String, Backend>,
+}
+
+impl Backends {
+    /// Load the backends, applying the configuration file if it exists.
+    pub fn load() -> Result<Backends, Error> {
+        let mut backends = Backends::default();
+        match fs::read_to_string(CONFIG_FILE) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            config => backends.configure(&config?)?,
+        }
+
+        Ok(backends)
+    }
+
+    /// Apply the configuration `config`.
+    fn configure(&mut self, config: &str) -> Result<(), Error> {
+        // The extension of the section we are in.
+        let mut extension = None;
+        for (n, line) in config.lines().enumerate() {
+            let line = line.trim();
+            // Skip empty lines and comments.
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let error = |msg: &str| failure::err_msg(format!("{}: {} at line {}", CONFIG_FILE, msg, n + 1));
+            if line.starts_with('[') && line.ends_with(']') {
+                // Start a new section.
+                let ext = line[1..line.len() - 1].trim().strip_prefix("backend ")
+                    .ok_or_else(|| error("unknown section"))?
+                    .trim()
+                    .trim_start_matches('.')
+                    .to_string();
+                // Start from the built-in backend, if any.
+                self.backends.entry(ext.clone()).or_insert(Backend {
+                    comment: Comment::Line("%".to_string()),
+                    compiler: Compiler::Custom(String::new()),
+                });
+                extension = Some(ext);
+            } else {
+                let backend = extension.as_ref()
+                    .and_then(|ext| self.backends.get_mut(ext))
+                    .ok_or_else(|| error("key outside of a backend section"))?;
+                let colon = line.find(':').ok_or_else(|| error("expected key-value pair"))?;
+                let value = line[colon + 1..].trim();
+                match line[..colon].trim() {
+                    "command" => backend.compiler = Compiler::Custom(value.to_string()),
+                    "comment" if value == "<!--" => backend.comment = Comment::Html,
+                    "comment" => backend.comment = Comment::Line(value.to_string()),
+                    _ => return Err(error("unknown key")),
+                }
+            }
+        }
+
+        // Every custom backend must have a command.
+        for (ext, backend) in &self.backends {
+            if backend.compiler == Compiler::Custom(String::new()) {
+                return Err(failure::err_msg(format!("{}: backend '{}' has no command", CONFIG_FILE, ext)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the backend of the source at `path`, if it is a source at all.
+    pub fn get(&self, path: &path::Path) -> Option<&Backend> {
+        self.backends.get(&*path.extension()?.to_string_lossy())
+    }
+
+    /// Get the backend of the source at `path`, falling back to scripts for executable files.
+    ///
+    /// Executables are only scripts if they are marked as such. Other executables, including
+    /// binaries and files that cannot be read, are not sources.
+    pub fn find(&self, path: &path::Path) -> Option<Backend> {
+        if let Some(backend) = self.get(path) {
+            return Some(backend.clone());
+        }
+
+        // Check if any of the executable bits are set.
+        if fs::metadata(path).ok()?.permissions().mode() & 0o111 == 0 {
+            return None;
+        }
+        let content = String::from_utf8(fs::read(path).ok()?).ok()?;
+        if is_script(&content) {
+            Some(Backend::script())
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for Backends {
+    /// The built-in backends.
+    fn default() -> Backends {
+        let mut backends = HashMap::new();
+        backends.insert("tex".to_string(), Backend {
+            comment: Comment::Line("%".to_string()),
+            compiler: Compiler::Latexmk,
+        });
+        backends.insert("md".to_string(), Backend {
+            comment: Comment::Html,
+            compiler: Compiler::Pandoc,
+        });
+        backends.insert("typ".to_string(), Backend {
+            comment: Comment::Line("//".to_string()),
+            compiler: Compiler::Typst,
+        });
+
+        Backends { backends }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments() {
+        assert_eq!(Comment::Html.strip("<!-- tags: Fact -->"), Some(" tags: Fact "));
+        assert_eq!(Comment::Html.strip("# Title"), None);
+        assert_eq!(Comment::Line("//".to_string()).strip("//priority: 3"), Some("priority: 3"));
+    }
+
+    #[test]
+    fn configure() {
+        let mut backends = Backends::default();
+        backends.configure("
+# Org mode.
+[backend org]
+command: org2pdf \"$1\" \"$2\"
+comment: #+
+
+[backend md]
+comment: %
+").unwrap();
+        assert_eq!(backends.get(path::Path::new("a/b.org")), Some(&Backend {
+            comment: Comment::Line("#+".to_string()),
+            compiler: Compiler::Custom("org2pdf \"$1\" \"$2\"".to_string()),
+        }));
+        assert_eq!(backends.get(path::Path::new("b.md")), Some(&Backend {
+            comment: Comment::Line("%".to_string()),
+            compiler: Compiler::Pandoc,
+        }));
+        assert_eq!(backends.get(path::Path::new("c.txt")), None);
+        assert!(Backends::default().configure("[backend org]\ncomment: #").is_err());
+    }
+
+    #[test]
+    fn scripts() {
+        use std::{env, process};
+
+        let dir = env::temp_dir().join(format!("mkmu-scripts-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let files: &[(&str, &[u8])] = &[
+            ("binary", b"\x7fELF\xff\xfe\x00"),
+            ("helper", b"#!/bin/sh\n# Build the figures.\nmake figures\n"),
+            ("card", b"#!/bin/sh\n# mu: card\n# tags: Shell\nread answer\n"),
+        ];
+        for &(name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+            fs::set_permissions(dir.join(name), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        fs::write(dir.join("unmarked.txt"), "# mu: card\n").unwrap();
+
+        let backends = Backends::default();
+        let found: Vec<_> = ["binary", "helper", "card", "unmarked.txt", "missing"].iter()
+            .map(|name| backends.find(&dir.join(name)))
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found, [None, None, Some(Backend::script()), None, None]);
+    }
+}
@@ -0,0 +1,225 @@
+//! Concurrent compilation of cards.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{atomic, mpsc, Arc, Mutex};
+use std::{fs, io, path, process, thread};
+
+/// A source that is to be compiled.
+pub struct Job {
+    /// The key identifying the source (see `Source::key`).
+    pub source: path::PathBuf,
+    /// The command line compiling the source.
+    ///
+    /// It is given the source itself, unless it is a card body, in which case it is given the body
+    /// wrapped into its template.
+    pub command: Vec<String>,
+    /// The directory in which the output is placed.
+    pub output_dir: path::PathBuf,
+    /// The path of the resulting PDF.
+    pub pdf: path::PathBuf,
+    /// The hash of the source and its dependencies.
+    pub hash: u64,
+    /// The name used to prefix the output of the compiler.
+    pub name: String,
+}
+
+/// The result of a compilation.
+pub struct Outcome {
+    /// Did the compilation succeed?
+    ///
+    /// This is `false` if the compiler could not be started at all.
+    pub success: bool,
+    /// The output of the compiler (standard output followed by standard error).
+    pub output: Vec<u8>,
+    /// Was the PDF from a previous compilation kept after this compilation failed?
+    pub kept_previous: bool,
+}
+
+impl Job {
+    /// The path to the LaTeX log of the compilation.
+    pub fn log(&self) -> path::PathBuf {
+        self.pdf.with_extension("log")
+    }
+
+    /// Compile the source by running its compiler.
+    ///
+    /// If the compilation fails, the PDF from the previous compilation (if any) is kept.
+    fn run(&self) -> Outcome {
+        // Move the previous PDF aside, such that it can be restored if the compilation fails.
+        let backup = self.pdf.with_extension("pdf.prev");
+        let has_backup = fs::rename(&self.pdf, &backup).is_ok();
+
+        let mut outcome = self.compile();
+        if has_backup {
+            if outcome.success {
+                // Errors are ignored, as a stale backup does no harm.
+                let _ = fs::remove_file(&backup);
+            } else {
+                outcome.kept_previous = fs::rename(&backup, &self.pdf).is_ok();
+            }
+        }
+
+        outcome
+    }
+
+    /// Run the compiler.
+    fn compile(&self) -> Outcome {
+        // Create the directory mirroring that of the source.
+        if let Err(err) = fs::create_dir_all(&self.output_dir) {
+            return Outcome {
+                success: false,
+                output: format!("failed to create {:?}: {}\n", self.output_dir, err).into_bytes(),
+                kept_previous: false,
+            };
+        }
+
+        let output = process::Command::new(&self.command[0])
+            .args(&self.command[1..])
+            // Do not let latexmk wait for input on errors.
+            .stdin(process::Stdio::null())
+            .output();
+
+        match output {
+            Ok(output) => Outcome {
+                success: output.status.success(),
+                output: {
+                    let mut combined = output.stdout;
+                    combined.extend(output.stderr);
+                    combined
+                },
+                kept_previous: false,
+            },
+            Err(err) => Outcome {
+                success: false,
+                output: format!("failed to run {}: {}\n", self.command[0], err).into_bytes(),
+                kept_previous: false,
+            },
+        }
+    }
+}
+
+/// Extract the errors from a LaTeX log.
+///
+/// TeX errors start with `!` and are followed by some context, ending with the line on which the
+/// error occured (`l.<line> ...`).
+pub fn log_excerpt(log: &str) -> String {
+    let mut excerpt = String::new();
+    let mut lines = log.lines();
+    while let Some(line) = lines.next() {
+        if line.starts_with('!') {
+            excerpt.push_str(line);
+            excerpt.push('\n');
+            // Add the context of the error, but not too much of it.
+            for line in lines.by_ref().take(10) {
+                excerpt.push_str(line);
+                excerpt.push('\n');
+                if line.starts_with("l.") {
+                    break;
+                }
+            }
+        }
+    }
+
+    excerpt
+}
+
+/// The default number of concurrent jobs: the number of CPUs.
+pub fn default_threads() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// Compile `jobs` using `threads` concurrent workers.
+///
+/// `report` is called with each job and its outcome in the order of `jobs`
+/// (regardless of the order in which they finish), such that the output is deterministic. If
+/// `stop_on_failure` is set, no new jobs are started after a job has failed, and the jobs which
+/// were never started are not reported.
+pub fn run<F>(jobs: Vec<Job>, threads: usize, stop_on_failure: bool, mut report: F) -> io::Result<()>
+    where F: FnMut(&Job, Outcome) -> io::Result<()>
+{
+    let jobs = Arc::new(jobs);
+    // The indices of the jobs that have not been started yet.
+    let queue = Arc::new(Mutex::new((0..jobs.len()).collect::<VecDeque<_>>()));
+    // Set when a job failed and `stop_on_failure` is set.
+    let stop = Arc::new(atomic::AtomicBool::new(false));
+
+    let (send, recv) = mpsc::channel();
+    let workers: Vec<_> = (0..threads.max(1)).map(|_| {
+        let (jobs, queue, stop, send) = (jobs.clone(), queue.clone(), stop.clone(), send.clone());
+        thread::spawn(move || {
+            while !stop.load(atomic::Ordering::SeqCst) {
+                // Take the next job, if any.
+                let next = queue.lock().unwrap().pop_front();
+                let n = match next {
+                    Some(n) => n,
+                    None => break,
+                };
+
+                let outcome = jobs[n].run();
+                if !outcome.success && stop_on_failure {
+                    stop.store(true, atomic::Ordering::SeqCst);
+                }
+                // The receiver only hangs up if reporting failed, in which case we just quit.
+                if send.send((n, outcome)).is_err() {
+                    break;
+                }
+            }
+        })
+    }).collect();
+    // Drop our sender, such that the receiver stops once the workers are done.
+    drop(send);
+
+    // Report the outcomes in order. Outcomes finishing early are kept until it is their turn.
+    let mut finished = BTreeMap::new();
+    let mut next = 0;
+    let mut result = Ok(());
+    for (n, outcome) in recv {
+        finished.insert(n, outcome);
+        while let Some(outcome) = finished.remove(&next) {
+            if result.is_ok() {
+                result = report(&jobs[next], outcome);
+                // Stop the workers if reporting failed.
+                if result.is_err() {
+                    stop.store(true, atomic::Ordering::SeqCst);
+                }
+            }
+            next += 1;
+        }
+    }
+
+    for worker in workers {
+        // Workers do not panic unless something is seriously wrong.
+        worker.join().expect("compilation worker panicked");
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn excerpt() {
+        assert_eq!(log_excerpt(r"This is pdfTeX, Version 3.14159265
+(./yoneda.tex
+LaTeX2e <2018-12-01>
+! Undefined control sequence.
+l.7 \Hom
+         (A, -)
+[1] )
+! Missing $ inserted.
+<inserted text>
+                $
+l.9 \end{document}
+
+Output written on yoneda.pdf (1 page).
+"), r"! Undefined control sequence.
+l.7 \Hom
+! Missing $ inserted.
+<inserted text>
+                $
+l.9 \end{document}
+");
+    }
+}
@@ -1,16 +1,25 @@
 extern crate failure;
 extern crate clap;
-extern crate walkdir;
+extern crate ignore;
+extern crate inotify;
+extern crate mu_backend;
+
+mod backend;
+mod compile;
+mod manifest;
+mod source;
+mod template;
+mod walk;
+mod watch;
 
 // TODO: Add help page for formatting of settings.mu files.
 
 use failure::Error;
 use clap::{Arg, App};
-use walkdir::WalkDir;
 
-use std::{fs, path, process};
-use std::io::{self, Write, Read};
-use std::ffi::OsStr;
+use std::collections::HashSet;
+use std::{env, fs, path, process};
+use std::io::{self, Write};
 
 /// Start mu with stdout `stdout`.
 fn main_err() -> Result<(), Error> {
@@ -20,100 +29,422 @@ fn main_err() -> Result<(), Error> {
 
     let matches = App::new("mkmu")
         .version("0.1.0")
-        .about("Compiles a Mu deck from TeX files")
+        .about("Compiles a Mu deck from TeX, Markdown, Typst and other files")
         .arg(Arg::with_name("OUTPUT")
              .help("the directory in which the deck will be stored")
              .default_value("deck"))
+        .arg(Arg::with_name("force")
+             .short("f")
+             .long("force")
+             .help("Recompiles every card, even those that are up to date"))
+        .arg(Arg::with_name("jobs")
+             .short("j")
+             .long("jobs")
+             .value_name("N")
+             .help("Sets the number of cards compiled concurrently [default: the number of CPUs]")
+             .takes_value(true))
+        .arg(Arg::with_name("keep-going")
+             .short("k")
+             .long("keep-going")
+             .help("Continues past failed compilations, leaving out cards without a previous PDF"))
+        .arg(Arg::with_name("dir-tags")
+             .long("dir-tags")
+             .help("Adds the names of the directories containing a card to its tags"))
+        .arg(Arg::with_name("source")
+             .short("C")
+             .long("source")
+             .value_name("DIR")
+             .help("Sets the directory containing the sources [default: the current directory]")
+             .takes_value(true))
+        .arg(Arg::with_name("include")
+             .long("include")
+             .value_name("GLOB")
+             .help("Only compiles sources matching the glob (can be given multiple times)")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("exclude")
+             .long("exclude")
+             .value_name("GLOB")
+             .help("Skips sources matching the glob (can be given multiple times)")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("watch")
+             .short("w")
+             .long("watch")
+             .help("Keeps running, rebuilding the deck whenever a source changes (implies --keep-going)"))
         .get_matches();
 
+    // The number of concurrent compilations.
+    let threads = match matches.value_of("jobs") {
+        Some(jobs) => jobs.parse()?,
+        None => compile::default_threads(),
+    };
+
     // The output directory.
-    let output_dir = path::PathBuf::from(matches.value_of("OUTPUT").unwrap());
+    let mut output_dir = path::PathBuf::from(matches.value_of("OUTPUT").unwrap());
     fs::create_dir_all(&output_dir)?;
 
-    // The eventual content of the `.mu` deck file.
-    let mut deck = String::new();
+    // Change the directory to the source root. Paths of sources (and their dependencies) are
+    // relative to it, but the output directory is relative to the original directory.
+    if let Some(root) = matches.value_of("source") {
+        output_dir = fs::canonicalize(&output_dir)?;
+        env::set_current_dir(root)?;
+    }
 
-    // Load the settings, if it exists.
-    match fs::File::open("settings.mu") {
-        // The file does not exist. Do nothing.
-        Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
-        // The file exists. Read it.
-        settings_file => { settings_file?.read_to_string(&mut deck)?; },
+    let watch = matches.is_present("watch");
+    let mut options = Options {
+        output_dir,
+        force: matches.is_present("force"),
+        threads,
+        keep_going: watch || matches.is_present("keep-going"),
+        dir_tags: matches.is_present("dir-tags"),
+        include: matches.values_of("include").into_iter().flatten().collect(),
+        exclude: matches.values_of("exclude").into_iter().flatten().collect(),
+        watch,
+    };
+    if !watch {
+        return build(&mut stdout, &options);
     }
 
-    // Go over every entry in the current directory and compile the files.
-    for entry in WalkDir::new(".") {
-        // Throw error if necessary.
-        let entry = entry?;
-        if entry.file_type().is_file() {
-            // The entry was a file.
-
-            // Get the path.
-            let path = entry.path();
-            if path.extension() == Some(OsStr::new("tex")) {
-                // Read TeX file.
-                let mut tex_file = fs::File::open(&path)?;
-                let mut tex = String::new();
-                tex_file.read_to_string(&mut tex)?;
-
-                // Skip if there is no metadata.
-                if !tex.starts_with("%") {
-                    writeln!(stdout, "Skipping {:?} due to lack of metadata (file must start with `%`)", path)?;
-                    continue;
-                }
+    // Start watching before the first build, such that no change is missed.
+    let mut watcher = watch::Watcher::new(path::Path::new("."), walk::Filter::new(&options.output_dir, &options.include, &options.exclude)?)?;
+    loop {
+        // Report errors, but keep watching, as they are likely fixed by the next change.
+        if let Err(err) = build(&mut stdout, &options) {
+            writeln!(stdout, "mkmu error: {}", err)?;
+        }
+        // Only the first build is forced.
+        options.force = false;
+        writeln!(stdout, "Watching for changes...")?;
 
-                // Write section.
-                deck.push_str(&format!("[card {}]\n", path.file_stem().unwrap().to_str().unwrap()));
-                // Go over key-value pairs.
-                for line in tex.lines().take_while(|line| line.starts_with("%")) {
-                    // Rid the `%` starting the comment and trim spaces.
-                    let line = line[1..].trim();
-                    // Add the key-value pair.
-                    deck.push_str(line);
-                    // Append newline.
-                    deck.push('\n');
-                }
+        // The backends may have been reconfigured by the build, and a broken configuration is
+        // reported by the next build.
+        let changed = watcher.wait(&backend::Backends::load().unwrap_or_default())?;
+        writeln!(stdout, "——— {} changed ———", changed.iter()
+            .map(|path| format!("{:?}", path))
+            .collect::<Vec<_>>()
+            .join(", "))?;
+    }
+}
 
-                // This is a TeX file.
-                writeln!(stdout, "Compiling {:?}", path)?;
-
-                // Run latexmk to compile the file.
-                if !process::Command::new("latexmk")
-                    // TODO: Don't do unwrap.
-                    // Compile files into deck directory.
-                    .arg(format!("-outdir={}", &output_dir.to_str().unwrap()))
-                    // Set path to TeX file.
-                    .arg(path.as_os_str())
-                    // Start compilation.
-                    .spawn()?
-                    // Wait 'till it finishes.
-                    .wait()?
-                    // Determine if the command succeeded or not.
-                    .success()
-                {
-                    // The command failed. Throw error.
-                    return Err(failure::err_msg("Compilation failed."));
+/// The options of a build.
+struct Options<'a> {
+    /// The directory in which the deck is stored.
+    output_dir: path::PathBuf,
+    /// Should every card be recompiled?
+    force: bool,
+    /// The number of concurrent compilations.
+    threads: usize,
+    /// Should failed compilations be skipped?
+    keep_going: bool,
+    /// Should the names of directories be added to the tags?
+    dir_tags: bool,
+    /// The globs of the sources to include.
+    include: Vec<&'a str>,
+    /// The globs of the sources to exclude.
+    exclude: Vec<&'a str>,
+    /// Is mkmu watching for changes?
+    watch: bool,
+}
+
+/// Build the deck from the sources in the current directory, writing the progress to `stdout`.
+fn build<W: Write>(stdout: &mut W, options: &Options) -> Result<(), Error> {
+    let output_dir = &options.output_dir;
+    // Load the manifest of previously compiled sources, unless everything is to be rebuilt.
+    let manifest_path = output_dir.join(manifest::FILE_NAME);
+    let mut manifest = if options.force {
+        manifest::Manifest::default()
+    } else {
+        manifest::Manifest::load(&manifest_path)?
+    };
+
+    // The settings part of the `.mu` deck file.
+    let mut settings = source::Generated::default();
+
+    // Load the settings, if it exists, along with the files it includes.
+    let settings_path = path::Path::new("settings.mu");
+    if settings_path.exists() {
+        read_settings(settings_path, &mut settings, &mut Vec::new())?;
+    }
+
+    // The backends compiling the sources.
+    let backends = backend::Backends::load()?;
+    // The sources of the cards.
+    let mut sources = Vec::new();
+    // The metadata inherited from directories.
+    let mut inherited = source::Inherited::new(options.dir_tags);
+    // Go over the selected files in the source root and find the card sources.
+    let filter = walk::Filter::new(output_dir, &options.include, &options.exclude)?;
+    for path in filter.files(path::Path::new("."))? {
+        // Skip files without a backend, as well as templates, which are not cards themselves.
+        let backend = match backends.find(&path) {
+            Some(backend) if !(backend.templated() && template::is_template(&path)) => backend,
+            _ => continue,
+        };
+        // Read the source, skipping it if there is no metadata.
+        let read = source::Source::read(&path, &backend)?;
+        if read.is_empty() {
+            writeln!(stdout, "Skipping {:?} due to lack of metadata (file must start with comments)", path)?;
+        }
+        for mut source in read {
+            source.inherit(inherited.get(path.parent().unwrap_or_else(|| path::Path::new("")))?);
+            sources.push(source);
+        }
+    }
+
+    // Check that card IDs are unique before compiling anything.
+    let collisions = source::collisions(&sources);
+    if !collisions.is_empty() {
+        for collision in &collisions {
+            writeln!(stdout, "{}", collision)?;
+        }
+        return Err(failure::err_msg("Card IDs collide; use distinct paths or `%id:` keys."));
+    }
+
+    // Check the metadata before compiling anything, by parsing the deck as mu would. Decks
+    // without cards are not checked, as they are rejected for that reason alone.
+    if !sources.is_empty() {
+        let deck = assemble(&settings, &sources);
+        let (_, diagnostics) = mu_backend::Deck::parse_with_diagnostics(&deck.text);
+        // Report the problems at their origin in the sources.
+        let locate = |span: mu_backend::Span| match deck.origin(span.line - 1) {
+            Some(origin) => origin.to_string(),
+            None => "deck.mu".to_string(),
+        };
+        for warning in &diagnostics.warnings {
+            writeln!(stdout, "{}: warning: {}", locate(warning.span()), warning)?;
+        }
+        for err in &diagnostics.errors {
+            writeln!(stdout, "{}: {}", locate(err.span()), err.error())?;
+        }
+        if !diagnostics.errors.is_empty() {
+            return Err(failure::err_msg(format!("{} error(s) in the metadata of the cards.", diagnostics.errors.len())));
+        }
+    }
+
+    // The sources that need to be compiled.
+    let mut jobs = Vec::new();
+    // The number of sources that were up to date.
+    let mut skipped = 0;
+    // The templates that card bodies are wrapped into.
+    let mut templates = template::Templates::default();
+    // The files copied into the deck as they are.
+    let mut copied = Vec::new();
+    for source in &sources {
+        // Cards whose views are not compiled only need their files copied.
+        if !source.compiled() {
+            for file in source.copied() {
+                if !file.is_file() {
+                    return Err(failure::err_msg(format!("{:?} refers to {:?}, which does not exist.", source.key(), file)));
                 }
+                copied.push(file);
+            }
+            continue;
+        }
+
+        // Wrap card bodies into their templates and extract cards from files with multiple cards,
+        // placing the result in the build directory.
+        let input = if source.body || source.text.is_some() {
+            let mut document = match source.text {
+                Some(ref text) => text.clone(),
+                None => fs::read_to_string(&source.path)?,
+            };
+            if source.body {
+                document = templates.wrap(&document, &source.metadata.tags())
+                    .map_err(|err| failure::err_msg(format!("{:?}: {}", source.key(), err)))?;
+            }
+            let input = output_dir.join(template::BUILD_DIR)
+                .join(source.pdf().with_extension(source.path.extension().unwrap_or_default()));
+            template::write_if_changed(&input, &document)?;
+            input
+        } else {
+            source.path.clone()
+        };
+
+        // Compile it, placing the output in the directory mirroring that of the source.
+        let pdf = output_dir.join(source.pdf());
+        let job_output_dir = pdf.parent().unwrap_or(output_dir).to_path_buf();
+        let backend = backends.get(&source.path).unwrap();
+        let command = backend.command(&input, &job_output_dir, &pdf);
 
-                // TODO: Don't do unwrap.
-                // Generate metadata from the first comments in the TeX file.
+        // Skip the file if neither it, its dependencies (including the template) nor the command
+        // changed since it was last compiled, and its output is still there.
+        let hash = manifest::hash_source(&input, &backend.compiler, &command)?;
+        if manifest.is_fresh(&source.key(), hash) && pdf.is_file() {
+            // Do not clutter the output while watching.
+            if !options.watch {
+                writeln!(stdout, "Skipping {:?} (up to date)", source.key())?;
             }
+            skipped += 1;
+            continue;
         }
+
+        jobs.push(compile::Job {
+            source: source.key(),
+            command,
+            output_dir: job_output_dir,
+            pdf,
+            hash,
+            name: source.id.clone(),
+        });
     }
 
-    // Get path to the deck file.
-    let mut deck_path = output_dir;
-    deck_path.push("deck.mu");
-    // Write the deck file.
-    fs::write(deck_path, deck.as_bytes())?;
+    // Compile the files concurrently, printing the output of each compilation prefixed by the
+    // name of the card.
+    let keep_going = options.keep_going;
+    let (mut succeeded, mut failed) = (0, 0);
+    let total = jobs.len();
+    // The failed sources, along with the relevant part of their log.
+    let mut failures = Vec::new();
+    // The sources that are left out of the deck, since they have no PDF.
+    let mut excluded = HashSet::new();
+    compile::run(jobs, options.threads, !keep_going, |job, outcome| {
+        writeln!(stdout, "Compiling {:?}", job.source)?;
+        for line in String::from_utf8_lossy(&outcome.output).lines() {
+            writeln!(stdout, "[{}] {}", job.name, line)?;
+        }
+
+        if outcome.success {
+            // Record the successful compilation.
+            manifest.insert(job.source.clone(), job.hash);
+            succeeded += 1;
+        } else {
+            if outcome.kept_previous {
+                writeln!(stdout, "[{}] compilation failed; keeping previous PDF", job.name)?;
+            } else {
+                writeln!(stdout, "[{}] compilation failed; leaving out card", job.name)?;
+                excluded.insert(job.source.clone());
+            }
+            failed += 1;
+
+            // Find the relevant part of the log, or fall back to the output of the compiler.
+            let excerpt = fs::read_to_string(job.log())
+                .map(|log| compile::log_excerpt(&log))
+                .unwrap_or_default();
+            failures.push((job.source.clone(), if excerpt.is_empty() {
+                String::from_utf8_lossy(&outcome.output).into_owned()
+            } else {
+                excerpt
+            }));
+        }
+
+        Ok(())
+    })?;
+
+    // Write the manifest, keeping track of what has been compiled, even if something failed.
+    manifest.save(&manifest_path)?;
+    // Print a summary.
+    writeln!(stdout, "{} compiled, {} skipped, {} failed, {} not compiled",
+        succeeded,
+        skipped,
+        failed,
+        total - succeeded - failed,
+    )?;
+
+    // Write a report of the failures, or remove the report of the last run if nothing failed.
+    let report_path = output_dir.join("mkmu-report.txt");
+    if failures.is_empty() {
+        match fs::remove_file(&report_path) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            result => result?,
+        }
+    } else {
+        let mut report = String::new();
+        for (source, excerpt) in &failures {
+            report.push_str(&format!("——— {} ———\n{}\n", source.display(), excerpt));
+        }
+        fs::write(&report_path, report)?;
+        if options.watch {
+            // Show the errors right away.
+            for (source, excerpt) in &failures {
+                writeln!(stdout, "——— {} ———\n{}", source.display(), excerpt.trim_end())?;
+            }
+        } else {
+            writeln!(stdout, "See {:?} for details about the failures.", report_path)?;
+        }
+    }
+
+    if failed != 0 && !keep_going {
+        return Err(failure::err_msg("Compilation failed."));
+    }
+
+    // Copy the scripts and the existing PDFs, mirroring the directory structure of the sources.
+    for file in &copied {
+        let target = output_dir.join(file);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(file, target)?;
+    }
+
+    // Assemble the deck from the settings and the sections of the cards that were not left out.
+    let deck = assemble(&settings, sources.iter().filter(|source| !excluded.contains(&source.key())));
+
+    // Write the deck file, replacing the old one atomically, such that mu never reads a partial
+    // deck.
+    let deck_path = output_dir.join("deck.mu");
+    let tmp_path = output_dir.join("deck.mu.tmp");
+    fs::write(&tmp_path, deck.text.as_bytes())?;
+    fs::rename(tmp_path, deck_path)?;
+
+    if failed != 0 {
+        return Err(failure::err_msg(format!("{} card(s) failed to compile.", failed)));
+    }
+
+    Ok(())
+}
+
+/// Read the settings file at `path` into `settings`.
+///
+/// Included files are inlined, such that the deck does not depend on files outside of it.
+/// `including` holds the canonical paths of the files being read, each included by the previous
+/// one.
+fn read_settings(path: &path::Path, settings: &mut source::Generated, including: &mut Vec<path::PathBuf>) -> Result<(), Error> {
+    let content = fs::read_to_string(path)?;
+    including.push(path.canonicalize()?);
+    for (n, line) in content.lines().enumerate() {
+        let origin = source::Origin {
+            file: path.to_path_buf(),
+            line: Some(n + 1),
+        };
+        let included = match mu_backend::included(line) {
+            // Resolve the path relative to the including file.
+            Some(included) => path.parent().unwrap_or_else(|| path::Path::new("")).join(included),
+            None => {
+                settings.push(line, origin);
+                continue;
+            },
+        };
+
+        let canonical = included.canonicalize()
+            .map_err(|err| failure::err_msg(format!("{}: cannot read included file {:?} ({})", origin, included, err)))?;
+        if including.contains(&canonical) {
+            return Err(failure::err_msg(format!("{}: including {:?} would form a cycle", origin, included)));
+        }
+        read_settings(&included, settings, including)?;
+    }
+    including.pop();
 
     Ok(())
 }
 
+/// Assemble the `.mu` deck file from the settings `settings` and the sections of `sources`.
+fn assemble<'a, I: IntoIterator<Item = &'a source::Source>>(settings: &source::Generated, sources: I) -> source::Generated {
+    let mut deck = settings.clone();
+    for source in sources {
+        source.section(&mut deck);
+    }
+
+    deck
+}
+
 fn main() -> Result<(), Error> {
     if let Err(err) = main_err() {
         // Handle errors.
         eprintln!("mkmu error: {}", err);
+        process::exit(1);
     }
 
     Ok(())
@@ -0,0 +1,396 @@
+//! Tracking of compiled sources for incremental compilation.
+//!
+//! The manifest is stored in the output directory and records a hash of every source that was
+//! compiled successfully. The hash covers the command compiling the source, the content of the
+//! source and every file it depends on (e.g. through `\input`, `\include` and `\includegraphics`
+//! in TeX), including shared preambles, so a card is only recompiled when something that affects
+//! its output changed.
+
+use std::collections::{HashMap, HashSet};
+use std::{fs, io, path};
+
+use crate::backend::Compiler;
+
+/// The name of the manifest file in the output directory.
+pub const FILE_NAME: &str = ".mkmu-manifest";
+
+/// The extensions tried when a graphics file is included without one.
+const GRAPHICS_EXTENSIONS: [&str; 5] = ["pdf", "png", "jpg", "jpeg", "eps"];
+
+/// A manifest of compiled sources.
+#[derive(Default)]
+pub struct Manifest {
+    /// The hashes of the sources at the time they were last compiled.
+    hashes: HashMap<path::PathBuf, u64>,
+}
+
+impl Manifest {
+    /// Load the manifest from `path`.
+    ///
+    /// If the file does not exist, an empty manifest is returned. Malformed lines are ignored,
+    /// which simply causes the respective sources to be recompiled.
+    pub fn load(path: &path::Path) -> io::Result<Manifest> {
+        let content = match fs::read_to_string(path) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Manifest::default()),
+            content => content?,
+        };
+
+        // Each line is of the form `<hash> <source>`.
+        let hashes = content.lines()
+            .filter_map(|line| {
+                let mut split = line.splitn(2, ' ');
+                let hash = u64::from_str_radix(split.next()?, 16).ok()?;
+                Some((path::PathBuf::from(split.next()?), hash))
+            })
+            .collect();
+
+        Ok(Manifest { hashes })
+    }
+
+    /// Write the manifest to `path`.
+    pub fn save(&self, path: &path::Path) -> io::Result<()> {
+        // Sort the entries to make the file deterministic.
+        let mut entries: Vec<_> = self.hashes.iter().collect();
+        entries.sort();
+
+        let mut content = String::new();
+        for (source, hash) in entries {
+            content.push_str(&format!("{:016x} {}\n", hash, source.display()));
+        }
+
+        fs::write(path, content)
+    }
+
+    /// Check if `source` was last compiled with hash `hash`.
+    pub fn is_fresh(&self, source: &path::Path, hash: u64) -> bool {
+        self.hashes.get(source) == Some(&hash)
+    }
+
+    /// Record that `source` was compiled with hash `hash`.
+    pub fn insert(&mut self, source: path::PathBuf, hash: u64) {
+        self.hashes.insert(source, hash);
+    }
+}
+
+/// A 64-bit FNV-1a hasher.
+///
+/// Unlike the hasher of the standard library, its output is stable, which is necessary as the
+/// hashes are stored on disk.
+struct Hasher(u64);
+
+impl Hasher {
+    /// Create a new hasher.
+    fn new() -> Hasher {
+        Hasher(0xcbf2_9ce4_8422_2325)
+    }
+
+    /// Feed `bytes` into the hasher.
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= u64::from(byte);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+        // Terminate the chunk, such that `write(a); write(b)` differs from `write(a + b)`.
+        self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+    }
+}
+
+/// A file that a source depends on.
+#[derive(Debug, PartialEq)]
+enum Dependency<'a> {
+    /// A TeX file, included by `\input` or `\include`.
+    Tex(&'a str),
+    /// A graphics file, included by `\includegraphics`.
+    Graphics(&'a str),
+    /// A Typst file, included by `#include` or `#import`.
+    Typst(&'a str),
+    /// Any other file, such as an image, which is used as it is.
+    File(&'a str),
+}
+
+/// A function finding the files that a source depends on.
+type Scanner = fn(&str) -> Vec<Dependency<'_>>;
+
+/// Get the function finding the dependencies of the sources compiled by `compiler`.
+///
+/// The dependencies of sources compiled by custom commands are unknown, so only the source itself
+/// is hashed for those.
+fn scanner(compiler: &Compiler) -> Scanner {
+    match *compiler {
+        Compiler::Latexmk => tex_dependencies,
+        Compiler::Pandoc => markdown_dependencies,
+        Compiler::Typst => typst_dependencies,
+        Compiler::Custom(_) | Compiler::Script => |_| Vec::new(),
+    }
+}
+
+/// Find the files that the TeX source `tex` depends on.
+///
+/// Commented out dependencies are skipped.
+fn tex_dependencies(tex: &str) -> Vec<Dependency<'_>> {
+    let mut deps = Vec::new();
+    for mut line in tex.lines() {
+        // Strip comments (but not escaped percent signs).
+        if let Some(comment) = line.char_indices()
+            .find(|&(i, c)| c == '%' && !line[..i].ends_with('\\'))
+            .map(|(i, _)| i)
+        {
+            line = &line[..comment];
+        }
+
+        // The commands including files, and whether they include graphics.
+        for &(command, graphics) in &[("\\input", false), ("\\include", false), ("\\includegraphics", true)] {
+            let mut rest = line;
+            while let Some(start) = rest.find(command) {
+                rest = &rest[start + command.len()..];
+                // Skip optional arguments.
+                let mut arg = rest.trim_start();
+                if arg.starts_with('[') {
+                    match arg.find(']') {
+                        Some(end) => arg = arg[end + 1..].trim_start(),
+                        None => break,
+                    }
+                }
+                // Read the mandatory argument. This also ensures that `\include` does not match
+                // `\includegraphics`.
+                if arg.starts_with('{') {
+                    if let Some(end) = arg.find('}') {
+                        let name = arg[1..end].trim();
+                        deps.push(if graphics { Dependency::Graphics(name) } else { Dependency::Tex(name) });
+                    }
+                }
+            }
+        }
+    }
+
+    deps
+}
+
+/// Find the images that the Markdown source `markdown` depends on.
+///
+/// These are the local targets of images (`![alt](path "title")`); URLs are skipped.
+fn markdown_dependencies(markdown: &str) -> Vec<Dependency<'_>> {
+    let mut deps = Vec::new();
+    let mut rest = markdown;
+    while let Some(start) = rest.find("![") {
+        rest = &rest[start + 2..];
+        // Skip the alternative text, and read the target up to the optional title.
+        let target = match rest.find("](") {
+            Some(end) => &rest[end + 2..],
+            None => break,
+        };
+        let target = match target.find(')') {
+            // Targets in angle brackets can contain spaces.
+            Some(_) if target.starts_with('<') => match target.find('>') {
+                Some(end) => &target[1..end],
+                None => break,
+            },
+            Some(end) => target[..end].split_whitespace().next().unwrap_or(""),
+            None => break,
+        };
+        if !target.is_empty() && !target.contains("://") {
+            deps.push(Dependency::File(target));
+        }
+    }
+
+    deps
+}
+
+/// Find the files that the Typst source `typst` depends on.
+///
+/// These are the files included or imported (except packages), and the files read by `image` and
+/// the data loading functions. Commented out dependencies are skipped.
+fn typst_dependencies(typst: &str) -> Vec<Dependency<'_>> {
+    let mut deps = Vec::new();
+    for mut line in typst.lines() {
+        // Strip comments (but not URLs in strings, which are not dependencies anyway).
+        if let Some(comment) = line.find("//").filter(|&i| !line[..i].ends_with(':')) {
+            line = &line[..comment];
+        }
+
+        // The functions and keywords taking files, and whether the files are Typst files.
+        let functions = [
+            ("#include", true), ("#import", true),
+            ("image(", false), ("read(", false), ("csv(", false), ("json(", false), ("toml(", false),
+            ("yaml(", false), ("xml(", false),
+        ];
+        for &(function, typst) in &functions {
+            let mut rest = line;
+            while let Some(start) = rest.find(function) {
+                // Functions must not be the end of another name, such as `myimage`.
+                let preceded = rest[..start].chars().next_back()
+                    .is_some_and(|c| c.is_alphanumeric() || c == '_' || c == '-');
+                rest = &rest[start + function.len()..];
+                // Read the path, which is a string literal.
+                let arg = rest.trim_start();
+                if preceded || !arg.starts_with('"') {
+                    continue;
+                }
+                if let Some(end) = arg[1..].find('"') {
+                    let name = &arg[1..end + 1];
+                    // Packages are not files.
+                    if !name.starts_with('@') {
+                        deps.push(if typst { Dependency::Typst(name) } else { Dependency::File(name) });
+                    }
+                }
+            }
+        }
+    }
+
+    deps
+}
+
+/// Find the file a dependency refers to.
+///
+/// TeX resolves paths relative to the directory it is run in, and Typst relative to the directory
+/// of the source, so both are tried.
+fn resolve(dependency: &Dependency<'_>, source_dir: &path::Path) -> Option<path::PathBuf> {
+    let (name, extensions): (&str, &[&str]) = match dependency {
+        Dependency::Tex(name) => (name, &["tex"]),
+        Dependency::Graphics(name) => (name, &GRAPHICS_EXTENSIONS),
+        Dependency::Typst(name) | Dependency::File(name) => (name, &[]),
+    };
+
+    for dir in &[path::Path::new("."), source_dir] {
+        let path = dir.join(name);
+        if path.is_file() {
+            return Some(path);
+        }
+        for extension in extensions {
+            let path = path.with_extension(extension);
+            if path.is_file() {
+                return Some(path);
+            }
+        }
+    }
+
+    None
+}
+
+/// Hash the source `path`, which is compiled by `compiler` with the command line `command`.
+///
+/// This covers the command line, the content of the source and that of all its dependencies.
+pub fn hash_source(path: &path::Path, compiler: &Compiler, command: &[String]) -> io::Result<u64> {
+    let mut hasher = Hasher::new();
+    for arg in command {
+        hasher.write(arg.as_bytes());
+    }
+    let mut visited = HashSet::new();
+    hash_file(path, scanner(compiler), &mut hasher, &mut visited)?;
+    Ok(hasher.0)
+}
+
+/// Feed the content of the file `path` and its dependencies (found by `scanner`) into `hasher`.
+///
+/// `visited` holds the files already hashed, such that cyclic inputs do not cause infinite
+/// recursion.
+fn hash_file(path: &path::Path, scanner: Scanner, hasher: &mut Hasher, visited: &mut HashSet<path::PathBuf>) -> io::Result<()> {
+    if !visited.insert(path.to_path_buf()) {
+        return Ok(());
+    }
+
+    let content = fs::read(path)?;
+    hasher.write(&content);
+
+    let text = String::from_utf8_lossy(&content);
+    let source_dir = path.parent().unwrap_or_else(|| path::Path::new("."));
+    for dependency in scanner(&text) {
+        match (resolve(&dependency, source_dir), dependency) {
+            // Hash included TeX and Typst files recursively.
+            (Some(path), Dependency::Tex(_)) => hash_file(&path, tex_dependencies, hasher, visited)?,
+            (Some(path), Dependency::Typst(_)) => hash_file(&path, typst_dependencies, hasher, visited)?,
+            (Some(path), Dependency::Graphics(_)) | (Some(path), Dependency::File(_)) => if visited.insert(path.clone()) {
+                hasher.write(&fs::read(&path)?);
+            },
+            // The dependency does not exist (yet). Hash its name, such that the card is rebuilt
+            // when it appears.
+            (None, Dependency::Tex(name))
+            | (None, Dependency::Graphics(name))
+            | (None, Dependency::Typst(name))
+            | (None, Dependency::File(name)) => hasher.write(name.as_bytes()),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_dependencies() {
+        let deps = tex_dependencies(r"
+\input{preamble}
+\begin{document}
+\include{chapter} % \input{commented}
+\includegraphics[width=0.5\textwidth]{figures/torus} \input { spaced }
+100\% \input{escaped}
+\end{document}
+");
+        assert_eq!(deps, vec![
+            Dependency::Tex("preamble"),
+            Dependency::Tex("chapter"),
+            Dependency::Tex("spaced"),
+            Dependency::Graphics("figures/torus"),
+            Dependency::Tex("escaped"),
+        ]);
+    }
+
+    #[test]
+    fn markdown_and_typst_dependencies() {
+        let deps = markdown_dependencies(r#"
+# Torus
+![A torus](figures/torus.png "The torus") and ![](<with space.svg>)
+![Remote](https://example.com/a.png) [not an image](b.png)
+"#);
+        assert_eq!(deps, vec![Dependency::File("figures/torus.png"), Dependency::File("with space.svg")]);
+
+        let deps = typst_dependencies(r#"
+#import "@preview/cetz:0.2.0": canvas
+#import "lib.typ": theorem
+#include "chapter.typ" // #include "commented.typ"
+#figure(image("torus.png", width: 50%)) #myimage("not-a-file.png")
+#let data = json( "data.json" )
+"#);
+        assert_eq!(deps, vec![
+            Dependency::Typst("lib.typ"),
+            Dependency::Typst("chapter.typ"),
+            Dependency::File("torus.png"),
+            Dependency::File("data.json"),
+        ]);
+    }
+
+    #[test]
+    fn hashed_dependencies() {
+        use std::{env, process};
+
+        let dir = env::temp_dir().join(format!("mkmu-manifest-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("card.md");
+        fs::write(&source, "![Torus](torus.png)\n").unwrap();
+        fs::write(dir.join("torus.png"), "old").unwrap();
+        let command = ["pandoc".to_string()];
+        let hash = |compiler, command: &[String]| hash_source(&source, compiler, command).unwrap();
+
+        let old = hash(&Compiler::Pandoc, &command);
+        fs::write(dir.join("torus.png"), "new").unwrap();
+        let new = hash(&Compiler::Pandoc, &command);
+        // Changing the command changes the hash as well.
+        let custom = hash(&Compiler::Custom("md2pdf".to_string()), &["md2pdf".to_string()]);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_ne!(old, new);
+        assert_ne!(new, custom);
+    }
+
+    #[test]
+    fn hash_is_order_sensitive() {
+        let mut a = Hasher::new();
+        a.write(b"ab");
+        a.write(b"c");
+        let mut b = Hasher::new();
+        b.write(b"a");
+        b.write(b"bc");
+        assert_ne!(a.0, b.0);
+    }
+}
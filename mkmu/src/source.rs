@@ -0,0 +1,622 @@
+//! Card sources and their metadata.
+
+use std::collections::{BTreeMap, HashMap};
+use std::{fmt, fs, io, mem, path};
+
+use failure::Error;
+
+use crate::backend::{self, Backend, Compiler};
+use crate::template;
+
+/// The name of the files holding metadata for all cards in a directory.
+pub const META_FILE: &str = "_meta.mu";
+
+/// The location of a line in the sources.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Origin {
+    /// The file, relative to the source root.
+    pub file: path::PathBuf,
+    /// The (1-based) line number, if the line stems from a single line of the file.
+    pub line: Option<usize>,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}", self.file.display(), line),
+            None => write!(f, "{}", self.file.display()),
+        }
+    }
+}
+
+/// A line of metadata.
+#[derive(Clone, Debug, PartialEq)]
+struct Line {
+    /// The key-value pair.
+    text: String,
+    /// Where the line comes from, unless it was generated.
+    origin: Option<Origin>,
+}
+
+/// The metadata of a card.
+///
+/// This is the lines of key-value pairs of its section in the `.mu` file.
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Metadata {
+    /// The lines of key-value pairs.
+    lines: Vec<Line>,
+}
+
+/// Get the key of a key-value pair.
+fn key(line: &str) -> Option<&str> {
+    line.find(':').map(|colon| line[..colon].trim())
+}
+
+impl Metadata {
+    /// Parse metadata from the content of the metadata file `file`.
+    ///
+    /// Empty lines and comments (starting with `#`) are skipped.
+    pub fn parse(s: &str, file: &path::Path) -> Metadata {
+        let mut metadata = Metadata::default();
+        for (n, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                metadata.push(line.to_string(), Some(Origin {
+                    file: file.to_path_buf(),
+                    line: Some(n + 1),
+                }));
+            }
+        }
+
+        metadata
+    }
+
+    /// Add a line originating from `origin`.
+    pub fn push(&mut self, text: String, origin: Option<Origin>) {
+        self.lines.push(Line { text, origin });
+    }
+
+    /// Get the value of the last line with key `key`.
+    pub fn get(&self, key_: &str) -> Option<&str> {
+        self.lines.iter()
+            .rev()
+            .find(|line| key(&line.text) == Some(key_))
+            .map(|line| line.text[line.text.find(':').unwrap() + 1..].trim())
+    }
+
+    /// Get the tags.
+    pub fn tags(&self) -> Vec<String> {
+        self.lines.iter()
+            .filter(|line| key(&line.text) == Some("tags"))
+            .flat_map(|line| line.text[line.text.find(':').unwrap() + 1..].split(','))
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect()
+    }
+
+    /// Replace the tags by `tags`, removing duplicates.
+    fn set_tags(&mut self, tags: Vec<String>) {
+        let mut unique: Vec<String> = Vec::new();
+        for tag in tags {
+            if !unique.contains(&tag) {
+                unique.push(tag);
+            }
+        }
+
+        // Replace the first line of tags (keeping its origin) and remove the rest.
+        let text = format!("tags: {}", unique.join(", "));
+        match self.lines.iter().position(|line| key(&line.text) == Some("tags")) {
+            Some(n) => {
+                self.lines[n].text = text;
+                let mut first = true;
+                self.lines.retain(|line| key(&line.text) != Some("tags") || mem::replace(&mut first, false));
+            },
+            None if !unique.is_empty() => self.push(text, None),
+            None => (),
+        }
+    }
+
+    /// Add tags after the existing ones.
+    pub fn add_tags<'a, I: IntoIterator<Item = &'a str>>(&mut self, tags: I) {
+        let mut all = self.tags();
+        all.extend(tags.into_iter().map(str::to_string));
+        self.set_tags(all);
+    }
+
+    /// Override the metadata by `other`.
+    ///
+    /// The tags are combined (with the tags of `other` first, such that their settings take
+    /// precedence), whereas the other keys of `other` replace those of `self`.
+    pub fn merge(&mut self, other: &Metadata) {
+        // Combine the tags.
+        let mut tags = other.tags();
+        tags.extend(self.tags());
+        self.set_tags(tags);
+
+        for line in &other.lines {
+            match key(&line.text) {
+                Some("tags") => (),
+                Some(key_) => {
+                    self.lines.retain(|existing| key(&existing.text) != Some(key_));
+                    self.lines.push(line.clone());
+                },
+                // Keep malformed lines, such that they can be reported.
+                None => self.lines.push(line.clone()),
+            }
+        }
+    }
+}
+
+/// A generated `.mu` file, along with the origins of its lines.
+#[derive(Clone, Default)]
+pub struct Generated {
+    /// The content of the file.
+    pub text: String,
+    /// The origin of each line.
+    origins: Vec<Origin>,
+}
+
+impl Generated {
+    /// Add the line `line` originating from `origin`.
+    pub fn push(&mut self, line: &str, origin: Origin) {
+        self.text.push_str(line);
+        self.text.push('\n');
+        self.origins.push(origin);
+    }
+
+    /// Get the origin of the line with (0-based) number `n`.
+    pub fn origin(&self, n: usize) -> Option<&Origin> {
+        self.origins.get(n)
+    }
+}
+
+/// The metadata inherited by cards from the directories containing them.
+pub struct Inherited {
+    /// Should the names of the directories be added as tags?
+    dir_tags: bool,
+    /// The inherited metadata of the directories visited so far.
+    cache: HashMap<path::PathBuf, Metadata>,
+}
+
+impl Inherited {
+    /// Create a new cache of inherited metadata.
+    pub fn new(dir_tags: bool) -> Inherited {
+        Inherited {
+            dir_tags,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Get the metadata inherited by the cards in `dir`.
+    ///
+    /// This combines the metadata files of `dir` and all its ancestors (up to the source root), as
+    /// well as the directory tags, if enabled.
+    pub fn get(&mut self, dir: &path::Path) -> io::Result<Metadata> {
+        if let Some(metadata) = self.cache.get(dir) {
+            return Ok(metadata.clone());
+        }
+
+        // Start with the metadata of the parent directory.
+        let mut metadata = match dir.parent() {
+            Some(parent) => self.get(parent)?,
+            None => Metadata::default(),
+        };
+        if self.dir_tags {
+            if let Some(name) = dir.file_name() {
+                metadata.add_tags(Some(&*name.to_string_lossy()));
+            }
+        }
+        // Apply the metadata file of the directory, if it exists.
+        match fs::read_to_string(dir.join(META_FILE)) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+            content => metadata.merge(&Metadata::parse(&content?, &dir.join(META_FILE))),
+        }
+
+        self.cache.insert(dir.to_path_buf(), metadata.clone());
+        Ok(metadata)
+    }
+}
+
+/// A source file of a card.
+///
+/// A file can contain multiple cards, each starting with a comment `card: <name>`. The text before
+/// the first card (including its metadata) is shared by all of them.
+pub struct Source {
+    /// The path to the source, relative to the source root.
+    pub path: path::PathBuf,
+    /// The name of the card within the file, if the file contains multiple cards.
+    card: Option<String>,
+    /// The ID of the card.
+    ///
+    /// This is the path of the source without its extension (followed by `/<name>` for cards in a
+    /// file with multiple cards), unless an ID is given explicitly by the `id` key in the header.
+    /// In files with multiple cards, an `id` key in the shared header replaces the path as the
+    /// prefix of the IDs.
+    pub id: String,
+    /// Was the ID given explicitly?
+    explicit_id: bool,
+    /// The metadata of the card.
+    ///
+    /// This is initially read from the header (except `id`), without the comment delimiters.
+    pub metadata: Metadata,
+    /// Is the source a card body, which must be wrapped into a template?
+    pub body: bool,
+    /// The text of the card, if it is not the whole file.
+    pub text: Option<String>,
+    /// Is the source a script, which is run by mu rather than compiled?
+    script: bool,
+}
+
+/// Parse the key-value pairs `lines` of a header of `file` into the ID (if given) and the other
+/// metadata.
+///
+/// The lines are given along with their (1-based) line numbers.
+fn parse_header<'a, I>(lines: I, file: &path::Path) -> (Option<String>, Metadata)
+    where I: IntoIterator<Item = (usize, &'a str)>
+{
+    let mut id = None;
+    let mut metadata = Metadata::default();
+    for (n, line) in lines {
+        // Trim spaces.
+        let line = line.trim();
+        match line.find(':') {
+            // Take the ID.
+            Some(colon) if line[..colon].trim() == "id" => id = Some(line[colon + 1..].trim().to_string()),
+            _ => metadata.push(line.to_string(), Some(Origin {
+                file: file.to_path_buf(),
+                line: Some(n),
+            })),
+        }
+    }
+
+    (id, metadata)
+}
+
+impl Source {
+    /// Read the sources in the file at `path`, which is compiled by `backend`.
+    ///
+    /// `path` is relative to the source root. The metadata is read from the comments at the start
+    /// of the file (and of each card, if it contains multiple). If the file has no metadata, no
+    /// sources are returned.
+    pub fn read(path: &path::Path, backend: &Backend) -> Result<Vec<Source>, Error> {
+        Source::parse(path, &fs::read_to_string(path)?, backend)
+    }
+
+    /// Parse the sources in the file at `path` with content `content`.
+    fn parse<'a>(path: &path::Path, content: &'a str, backend: &Backend) -> Result<Vec<Source>, Error> {
+        // Number the lines, skipping the shebang line of scripts.
+        let lines: Vec<(usize, &str)> = content.lines()
+            .enumerate()
+            .filter(|&(n, line)| n != 0 || !line.starts_with("#!"))
+            .map(|(n, line)| (n + 1, line))
+            .collect();
+        let script = backend.compiler == Compiler::Script;
+        // Get the content of the comments at the start, leaving out the marker of scripts.
+        let strip = |&(n, line): &(usize, &'a str)| backend.comment.strip(line).map(|line| (n, line));
+        let header: Vec<(usize, &str)> = lines.iter()
+            .map_while(strip)
+            .filter(|&(_, line)| !(script && line.trim() == backend::SCRIPT_MARKER))
+            .collect();
+        // Skip if there is no metadata.
+        if header.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Find the lines starting cards, along with their names. Scripts contain a single card.
+        let markers: Vec<(usize, String)> = lines.iter()
+            .take(if script { 0 } else { lines.len() })
+            .enumerate()
+            .filter_map(|(n, &(_, line))| {
+                let line = backend.comment.strip(line)?.trim();
+                let colon = line.find(':')?;
+                if line[..colon].trim() == "card" {
+                    Some((n, line[colon + 1..].trim().to_string()))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        if markers.is_empty() {
+            // The whole file is a single card.
+            let (id, metadata) = parse_header(header, path);
+            return Ok(vec![Source {
+                path: path.to_path_buf(),
+                card: None,
+                explicit_id: id.is_some(),
+                id: id.unwrap_or_else(|| path_id(path)),
+                metadata,
+                body: backend.templated() && template::is_body(content),
+                text: None,
+                script,
+            }]);
+        }
+
+        // The cards of TeX files are wrapped into templates, as they cannot all end the document,
+        // so the file must not be a complete document.
+        if backend.templated() && !template::is_body(content) {
+            let &(n, _) = lines.iter()
+                .find(|&&(_, line)| !template::is_body(line))
+                .unwrap_or(&lines[markers[0].0]);
+            let origin = Origin {
+                file: path.to_path_buf(),
+                line: Some(n),
+            };
+            return Err(failure::err_msg(format!(
+                "{}: a file with multiple cards must not be a complete document, as the cards are wrapped into templates",
+                origin,
+            )));
+        }
+
+        // The text before the first card is shared, and so is the metadata in it, unless the first
+        // card starts right away. An ID in it is the prefix of the IDs of the cards.
+        let shared = &lines[..markers[0].0];
+        let (prefix, shared_metadata) = parse_header(header.into_iter().take(shared.len()), path);
+
+        let mut sources = Vec::new();
+        for (i, &(start, ref name)) in markers.iter().enumerate() {
+            let end = markers.get(i + 1).map_or(lines.len(), |&(end, _)| end);
+            let block = &lines[start..end];
+            // Read the metadata following the marker.
+            let (id, own) = parse_header(block[1..].iter().map_while(strip), path);
+            let mut metadata = shared_metadata.clone();
+            metadata.merge(&own);
+
+            let mut text = String::new();
+            for &(_, line) in shared.iter().chain(block) {
+                text.push_str(line);
+                text.push('\n');
+            }
+            sources.push(Source {
+                path: path.to_path_buf(),
+                card: Some(name.clone()),
+                explicit_id: id.is_some() || prefix.is_some(),
+                id: id.unwrap_or_else(|| format!("{}/{}", prefix.clone().unwrap_or_else(|| path_id(path)), name)),
+                metadata,
+                body: backend.templated(),
+                text: Some(text),
+                script: false,
+            });
+        }
+
+        Ok(sources)
+    }
+
+    /// The key identifying the card among the sources.
+    ///
+    /// This is the path to the source, followed by `#<name>` for cards in a file with multiple
+    /// cards.
+    pub fn key(&self) -> path::PathBuf {
+        match self.card {
+            Some(ref name) => path::PathBuf::from(format!("{}#{}", self.path.display(), name)),
+            None => self.path.clone(),
+        }
+    }
+
+    /// Apply metadata inherited from the directories containing the source.
+    ///
+    /// The metadata of the source takes precedence.
+    pub fn inherit(&mut self, mut inherited: Metadata) {
+        inherited.merge(&self.metadata);
+        self.metadata = inherited;
+    }
+
+    /// Does the card need to be compiled?
+    ///
+    /// This is not the case for scripts and cards with their views given explicitly by `sh` or
+    /// `pdf` keys.
+    pub fn compiled(&self) -> bool {
+        !self.script && self.metadata.get("sh").is_none() && self.metadata.get("pdf").is_none()
+    }
+
+    /// The files that are copied into the deck as they are, relative to the source root.
+    ///
+    /// This is the script itself for scripts, and the PDFs given by `pdf` keys (unless their paths
+    /// are absolute).
+    pub fn copied(&self) -> Vec<path::PathBuf> {
+        if self.script {
+            return vec![self.path.clone()];
+        }
+
+        self.metadata.get("pdf")
+            .into_iter()
+            .flat_map(|pdfs| pdfs.split(','))
+            .map(|pdf| path::PathBuf::from(pdf.trim()))
+            .filter(|pdf| pdf.is_relative())
+            .collect()
+    }
+
+    /// The path of the resulting PDF, relative to the output directory.
+    ///
+    /// The output directory mirrors the directory structure of the sources, with a directory in
+    /// place of each file with multiple cards.
+    pub fn pdf(&self) -> path::PathBuf {
+        match self.card {
+            Some(ref name) => self.path.with_extension("").join(format!("{}.pdf", name)),
+            None => self.path.with_extension("pdf"),
+        }
+    }
+
+    /// Add the section of the card to the `.mu` deck file `deck`.
+    pub fn section(&self, deck: &mut Generated) {
+        // Lines without an origin of their own are attributed to the source file.
+        let origin = Origin {
+            file: self.path.clone(),
+            line: None,
+        };
+        deck.push(&format!("[card {}]", self.id), origin.clone());
+        if self.script {
+            // Scripts are run from the deck directory, in which they are placed like the PDFs.
+            deck.push(&format!("sh: {}", shell_quote(&format!("./{}", self.path.display()))), origin.clone());
+        } else if self.explicit_id && self.compiled() {
+            // If the ID was given explicitly, it does not determine the path to the PDF.
+            deck.push(&format!("pdf: {}", self.pdf().display()), origin.clone());
+        }
+        for line in &self.metadata.lines {
+            // Add the key-value pair.
+            deck.push(&line.text, line.origin.clone().unwrap_or_else(|| origin.clone()));
+        }
+    }
+}
+
+/// Derive a card ID from the path of its source.
+///
+/// This is the path without the extension and with components separated by `/`.
+fn path_id(path: &path::Path) -> String {
+    path.with_extension("")
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Quote `s` for `sh`, such that it is a single word taken literally.
+///
+/// Strings consisting only of characters without special meaning are left as they are.
+fn shell_quote(s: &str) -> String {
+    if !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || "/._-+,:@%".contains(c)) {
+        s.to_string()
+    } else {
+        // Nothing is special inside single quotes, except single quotes themselves, which are
+        // written as `'\''` (ending the quotes, escaping the quote and starting new quotes).
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+/// Find sources sharing the same card ID.
+///
+/// This returns a message describing each collision.
+pub fn collisions(sources: &[Source]) -> Vec<String> {
+    // Group the sources by their IDs.
+    let mut ids: BTreeMap<&str, Vec<path::PathBuf>> = BTreeMap::new();
+    for source in sources {
+        ids.entry(&source.id).or_default().push(source.key());
+    }
+
+    ids.into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|(id, paths)| format!("card ID '{}' is used by {}", id, paths.iter()
+            .map(|path| format!("{:?}", path))
+            .collect::<Vec<_>>()
+            .join(", ")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Backends;
+
+    /// Get the lines of key-value pairs of `metadata`.
+    fn texts(metadata: &Metadata) -> Vec<&str> {
+        metadata.lines.iter().map(|line| &*line.text).collect()
+    }
+
+    #[test]
+    fn ids_from_paths() {
+        assert_eq!(path_id(path::Path::new("algebra/definition.tex")), "algebra/definition");
+        assert_eq!(path_id(path::Path::new("yoneda.tex")), "yoneda");
+    }
+
+    #[test]
+    fn multiple_cards() {
+        let sources = Source::parse(path::Path::new("group/facts.md"), "<!-- tags: Group -->
+Let $G$ be a group.
+<!-- card: order -->
+<!-- tags: Order -->
+The order of $G$ is...
+<!-- card: lagrange -->
+<!-- id: lagrange -->
+Lagrange's theorem states...
+", Backends::default().get(path::Path::new("facts.md")).unwrap()).unwrap();
+        assert_eq!(sources.len(), 2);
+        assert_eq!(sources[0].id, "group/facts/order");
+        assert_eq!(sources[0].key(), path::Path::new("group/facts.md#order"));
+        assert_eq!(sources[0].pdf(), path::Path::new("group/facts/order.pdf"));
+        assert_eq!(texts(&sources[0].metadata), &["tags: Order, Group"]);
+        assert_eq!(sources[0].text.as_ref().unwrap(), "<!-- tags: Group -->
+Let $G$ be a group.
+<!-- card: order -->
+<!-- tags: Order -->
+The order of $G$ is...
+");
+        let mut deck = Generated::default();
+        sources[1].section(&mut deck);
+        assert_eq!(deck.text, "[card lagrange]\npdf: group/facts/lagrange.pdf\ntags: Group\n");
+        assert_eq!(deck.origin(2).unwrap().to_string(), "group/facts.md:1");
+    }
+
+    #[test]
+    fn shared_id() {
+        let tex = Backends::default().get(path::Path::new("a.tex")).unwrap().clone();
+        let sources = Source::parse(path::Path::new("algebra/groups.tex"), "%id: groups
+%tags: Group
+%card: order
+The order of an element $g$ is...
+%card: lagrange
+%id: lagrange
+Lagrange's theorem states...
+", &tex).unwrap();
+        assert_eq!(sources[0].id, "groups/order");
+        assert_eq!(sources[1].id, "lagrange");
+        assert!(sources[0].body);
+        // The ID does not determine the path to the PDF.
+        let mut deck = Generated::default();
+        sources[0].section(&mut deck);
+        assert_eq!(deck.text, "[card groups/order]\npdf: algebra/groups/order.pdf\ntags: Group\n");
+    }
+
+    #[test]
+    fn complete_document_with_cards() {
+        let tex = Backends::default().get(path::Path::new("a.tex")).unwrap().clone();
+        let err = Source::parse(path::Path::new("groups.tex"), "%tags: Group
+\\documentclass{article}
+\\begin{document}
+%card: order
+The order of an element $g$ is...
+\\end{document}
+", &tex).err().unwrap();
+        assert!(err.to_string().starts_with("groups.tex:2: "));
+    }
+
+    #[test]
+    fn quoted_scripts() {
+        assert_eq!(shell_quote("./algebra/quiz.sh"), "./algebra/quiz.sh");
+        assert_eq!(shell_quote("./my quiz; rm -rf ~"), "'./my quiz; rm -rf ~'");
+        assert_eq!(shell_quote("./it's $HOME"), "'./it'\\''s $HOME'");
+
+        let sources = Source::parse(path::Path::new("quiz $1.sh"), "#!/bin/sh\n# mu: card\n# tags: Shell\n", &Backend::script()).unwrap();
+        let mut deck = Generated::default();
+        sources[0].section(&mut deck);
+        assert_eq!(deck.text, "[card quiz $1]\nsh: './quiz $1.sh'\ntags: Shell\n");
+    }
+
+    #[test]
+    fn script_marker() {
+        let sources = Source::parse(path::Path::new("quiz"), "#!/bin/sh
+# mu: card
+# tags: Shell
+read answer
+", &Backend::script()).unwrap();
+        assert_eq!(sources.len(), 1);
+        assert_eq!(texts(&sources[0].metadata), &["tags: Shell"]);
+    }
+
+    #[test]
+    fn merge_metadata() {
+        let file = path::Path::new(META_FILE);
+        let mut metadata = Metadata::parse("
+# Defaults for the course.
+tags: Algebra, Course
+priority: 3
+max interval: 1y
+", file);
+        metadata.merge(&Metadata::parse("tags: Week 1, Algebra\npriority: 5\npriorityy: 2", file));
+        assert_eq!(texts(&metadata), &[
+            "tags: Week 1, Algebra, Course",
+            "max interval: 1y",
+            "priority: 5",
+            "priorityy: 2",
+        ]);
+    }
+}
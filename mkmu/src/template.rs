@@ -0,0 +1,143 @@
+//! Templates wrapping card bodies into full documents.
+//!
+//! A TeX source without `\documentclass` is a card body. Before it is compiled, it is inserted into
+//! a template, replacing the line `%body` of the template. The template is `templates/<tag>.tex`
+//! for the first tag of the card which has one (see `tag_template`), and otherwise `template.tex`
+//! in the source root.
+
+use std::collections::HashMap;
+use std::{fs, io, path};
+
+use failure::Error;
+
+/// The default template.
+pub const TEMPLATE_FILE: &str = "template.tex";
+/// The directory of templates for specific tags.
+pub const TEMPLATE_DIR: &str = "templates";
+/// The line in a template that is replaced by the body of the card.
+const BODY_MARKER: &str = "%body";
+/// The directory inside the output directory where the wrapped sources are placed.
+pub const BUILD_DIR: &str = ".build";
+
+/// Check if `path` (relative to the source root) is a template rather than a source.
+pub fn is_template(path: &path::Path) -> bool {
+    path == path::Path::new(TEMPLATE_FILE) || path.starts_with(TEMPLATE_DIR)
+}
+
+/// Check if the TeX source `tex` is a card body rather than a full document.
+pub fn is_body(tex: &str) -> bool {
+    !tex.contains("\\documentclass")
+}
+
+/// Get the path of the template of `tag`.
+///
+/// The components of hierarchical tags are directories, e.g. the template of `Math::Algebra` is
+/// `templates/Math/Algebra.tex`. Tags whose components cannot be file names (such as `..`) have no
+/// template.
+fn tag_template(tag: &str) -> Option<path::PathBuf> {
+    let mut path = path::PathBuf::from(TEMPLATE_DIR);
+    for component in tag.split(mu_backend::TAG_SEPARATOR) {
+        if component.is_empty() || component == "." || component == ".." || component.contains(path::is_separator) {
+            return None;
+        }
+        path.push(component);
+    }
+    // Append the extension, rather than replacing whatever follows a dot in the tag.
+    let mut name = path.into_os_string();
+    name.push(".tex");
+
+    Some(name.into())
+}
+
+/// A cache of templates.
+#[derive(Default)]
+pub struct Templates {
+    /// The templates loaded so far, or `None` if they do not exist.
+    loaded: HashMap<path::PathBuf, Option<String>>,
+}
+
+impl Templates {
+    /// Load the template at `path`, returning whether it exists.
+    fn load(&mut self, path: path::PathBuf) -> io::Result<bool> {
+        if !self.loaded.contains_key(&path) {
+            let template = match fs::read_to_string(&path) {
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+                template => Some(template?),
+            };
+            self.loaded.insert(path.clone(), template);
+        }
+
+        Ok(self.loaded[&path].is_some())
+    }
+
+    /// Wrap the card body `body` with tags `tags` into its template.
+    pub fn wrap(&mut self, body: &str, tags: &[String]) -> Result<String, Error> {
+        // Find the template of the first tag that has one, and fall back to the default template.
+        let mut found = None;
+        for path in tags.iter()
+            .filter_map(|tag| tag_template(tag))
+            .chain(Some(path::PathBuf::from(TEMPLATE_FILE)))
+        {
+            if self.load(path.clone())? {
+                found = Some(path);
+                break;
+            }
+        }
+        let path = found.ok_or_else(|| {
+            failure::err_msg(format!("card body has no template (create {:?})", TEMPLATE_FILE))
+        })?;
+        let template = self.loaded[&path].as_ref().unwrap();
+
+        // Replace the marker by the body.
+        if !template.lines().any(|line| line.trim() == BODY_MARKER) {
+            return Err(failure::err_msg(format!("template {:?} has no `{}` line", path, BODY_MARKER)));
+        }
+        let mut document = String::new();
+        for line in template.lines() {
+            document.push_str(if line.trim() == BODY_MARKER { body.trim_end_matches('\n') } else { line });
+            document.push('\n');
+        }
+
+        Ok(document)
+    }
+}
+
+/// Write `content` to `path`, unless the file already has that content.
+///
+/// This avoids touching wrapped sources that did not change.
+pub fn write_if_changed(path: &path::Path, content: &str) -> io::Result<()> {
+    if fs::read_to_string(path).ok().as_deref() == Some(content) {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    fs::write(path, content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_body() {
+        let mut templates = Templates::default();
+        templates.loaded.insert(path::PathBuf::from(TEMPLATE_FILE), Some(
+            "\\documentclass{beamer}\n\\begin{document}\n  %body\n\\end{document}\n".to_string()
+        ));
+        templates.loaded.insert(path::Path::new(TEMPLATE_DIR).join("Proof.tex"), None);
+        assert_eq!(
+            templates.wrap("%tags: Proof\nHello", &["Proof".to_string()]).unwrap(),
+            "\\documentclass{beamer}\n\\begin{document}\n%tags: Proof\nHello\n\\end{document}\n",
+        );
+    }
+
+    #[test]
+    fn tag_templates() {
+        assert_eq!(tag_template("Ch1.2"), Some(path::Path::new(TEMPLATE_DIR).join("Ch1.2.tex")));
+        assert_eq!(tag_template("Math::Algebra"), Some(path::Path::new(TEMPLATE_DIR).join("Math/Algebra.tex")));
+        assert_eq!(tag_template("Math::"), None);
+        assert_eq!(tag_template("../secret"), None);
+    }
+}
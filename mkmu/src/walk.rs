@@ -0,0 +1,159 @@
+//! Selection of source files.
+
+use std::path;
+
+use failure::Error;
+use ignore::gitignore::Gitignore;
+use ignore::{overrides, Match, WalkBuilder};
+
+/// The name of the files with gitignore-style patterns of files to skip.
+pub const IGNORE_FILE: &str = ".muignore";
+
+/// The selection of the files that are candidates for sources.
+///
+/// This skips hidden files (such as `.git`), files matched by `.muignore` files, and the output
+/// directory. Of the remaining files, only those matching one of the `--include` globs (if any) and
+/// none of the `--exclude` globs are selected.
+pub struct Filter {
+    /// The canonical path of the output directory.
+    output_dir: path::PathBuf,
+    /// The globs given on the command line.
+    globs: overrides::Override,
+}
+
+impl Filter {
+    /// Create the filter with output directory `output_dir` and globs `include` and `exclude`.
+    pub fn new(output_dir: &path::Path, include: &[&str], exclude: &[&str]) -> Result<Filter, Error> {
+        let mut globs = overrides::OverrideBuilder::new(".");
+        for glob in include {
+            globs.add(glob)?;
+        }
+        for glob in exclude {
+            globs.add(&format!("!{}", glob))?;
+        }
+
+        Ok(Filter {
+            // Compare paths in canonical form, such that the output directory is recognized
+            // regardless of how it was specified.
+            output_dir: output_dir.canonicalize()?,
+            globs: globs.build()?,
+        })
+    }
+
+    /// Find the selected files in the directory `root`.
+    ///
+    /// The paths returned are relative to `root`.
+    pub fn files(&self, root: &path::Path) -> Result<Vec<path::PathBuf>, Error> {
+        let output_dir = self.output_dir.clone();
+        // The globs are not given to the walker as overrides, since those take precedence over
+        // `.muignore`. Instead, the files are filtered afterwards.
+        let walker = WalkBuilder::new(root)
+            // Only use our own ignore files, not `.gitignore` and the like.
+            .standard_filters(false)
+            .hidden(true)
+            .add_custom_ignore_filename(IGNORE_FILE)
+            // Do not walk into the output directory.
+            .filter_entry(move |entry| {
+                !entry.file_type().is_some_and(|file_type| file_type.is_dir())
+                    || entry.path().canonicalize().map_or(true, |path| path != output_dir)
+            })
+            // Make the order deterministic.
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build();
+
+        let mut files = Vec::new();
+        for entry in walker {
+            let entry = entry?;
+            if entry.file_type().is_some_and(|file_type| file_type.is_file()) {
+                // Get the path relative to the root.
+                let path = entry.path().strip_prefix(root).unwrap_or_else(|_| entry.path());
+                if self.globs_select(path) {
+                    files.push(path.to_path_buf());
+                }
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Check if the globs given on the command line select the file `path`.
+    pub fn globs_select(&self, path: &path::Path) -> bool {
+        !self.globs.matched(path, false).is_ignore()
+    }
+
+    /// Check if the file or directory `path` (relative to the directory `root`) is skipped
+    /// regardless of the globs, as it is by `files`.
+    ///
+    /// The path does not need to exist, such that removed files can be checked as well.
+    pub fn skips(&self, root: &path::Path, path: &path::Path, is_dir: bool) -> bool {
+        // Skip hidden files and files in hidden directories.
+        if path.components().any(|component| match component {
+            path::Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+            _ => false,
+        }) {
+            return true;
+        }
+        // Skip the output directory and everything in it.
+        if path.ancestors()
+            .skip(if is_dir { 0 } else { 1 })
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .any(|dir| root.join(dir).canonicalize().is_ok_and(|dir| dir == self.output_dir))
+        {
+            return true;
+        }
+
+        // Check the ignore files of the directories containing the path, starting with the most
+        // specific one, which takes precedence.
+        for dir in path.ancestors().skip(1) {
+            let ignore_file = root.join(dir).join(IGNORE_FILE);
+            if !ignore_file.is_file() {
+                continue;
+            }
+            match Gitignore::new(&ignore_file).0.matched_path_or_any_parents(root.join(path), is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => (),
+            }
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    #[test]
+    fn muignore_and_globs() {
+        let root = env::temp_dir().join(format!("mkmu-walk-{}", process::id()));
+        fs::create_dir_all(root.join("deck")).unwrap();
+        fs::create_dir_all(root.join("drafts")).unwrap();
+        fs::write(root.join(IGNORE_FILE), "drafts/\nold.tex\n").unwrap();
+        for file in &["a.tex", "b.tex", "old.tex", "notes.md", "drafts/c.tex", "deck/d.tex"] {
+            fs::write(root.join(file), "").unwrap();
+        }
+
+        let filter = Filter::new(&root.join("deck"), &["*.tex"], &["b.tex"]).unwrap();
+        let files = filter.files(&root);
+        fs::remove_dir_all(&root).unwrap();
+        // The globs do not bring back files excluded by `.muignore`.
+        assert_eq!(files.unwrap(), [path::PathBuf::from("a.tex")]);
+    }
+
+    #[test]
+    fn skipped_paths() {
+        let root = env::temp_dir().join(format!("mkmu-skips-{}", process::id()));
+        fs::create_dir_all(root.join("algebra")).unwrap();
+        fs::write(root.join(IGNORE_FILE), "drafts/\n*.log\n").unwrap();
+        fs::write(root.join("algebra").join(IGNORE_FILE), "!keep.log\n").unwrap();
+
+        let filter = Filter::new(&root, &[], &[]).unwrap();
+        let skipped: Vec<_> = ["a.tex", "a.log", "drafts/b.tex", "algebra/keep.log", "algebra/.a.tex.swp", ".git"].iter()
+            .map(|path| filter.skips(&root, path::Path::new(path), false))
+            .collect();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(skipped, [false, true, true, false, true, true]);
+    }
+}
@@ -0,0 +1,223 @@
+//! Watching the source tree for changes.
+
+use std::collections::HashMap;
+use std::{fs, io, path, thread, time};
+
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+
+use crate::backend;
+use crate::template;
+use crate::walk;
+
+/// How long to wait for further changes after one was noticed.
+///
+/// Editors often save a file in several steps, and all of them should trigger a single rebuild.
+const DEBOUNCE: time::Duration = time::Duration::from_millis(100);
+
+/// The path returned when events were lost, meaning that anything may have changed.
+pub const EVERYTHING: &str = ".";
+
+/// A watcher of the source tree.
+pub struct Watcher {
+    /// The source root.
+    root: path::PathBuf,
+    /// The inotify instance.
+    inotify: Inotify,
+    /// The watched directories.
+    dirs: HashMap<WatchDescriptor, path::PathBuf>,
+    /// The selection of the sources, outside of which changes are irrelevant.
+    filter: walk::Filter,
+}
+
+impl Watcher {
+    /// Start watching the source root `root` and its subdirectories, except for those skipped by
+    /// `filter` (such as the output directory).
+    pub fn new(root: &path::Path, filter: walk::Filter) -> io::Result<Watcher> {
+        let mut watcher = Watcher {
+            root: root.to_path_buf(),
+            inotify: Inotify::init()?,
+            dirs: HashMap::new(),
+            filter,
+        };
+        watcher.watch(root)?;
+
+        Ok(watcher)
+    }
+
+    /// Check if a change to the file or directory `path` (relative to the source root) matters.
+    ///
+    /// Paths skipped by the filter do not, and neither do backups of editors. Sources must be
+    /// selected by the globs as well, while other files (such as templates, settings or files
+    /// included by sources) always matter.
+    fn relevant(&self, path: &path::Path, is_dir: bool, backends: &backend::Backends) -> bool {
+        !path.to_string_lossy().ends_with('~')
+            && !self.filter.skips(&self.root, path, is_dir)
+            && (is_dir
+                || template::is_template(path)
+                || backends.get(path).is_none()
+                || self.filter.globs_select(path))
+    }
+
+    /// Watch `dir` and its subdirectories.
+    fn watch(&mut self, dir: &path::Path) -> io::Result<()> {
+        let relative = dir.strip_prefix(&self.root).unwrap_or(dir);
+        if !relative.as_os_str().is_empty() && self.filter.skips(&self.root, relative, true) {
+            return Ok(());
+        }
+
+        let mask = WatchMask::CLOSE_WRITE
+            | WatchMask::CREATE
+            | WatchMask::DELETE
+            | WatchMask::MOVED_FROM
+            | WatchMask::MOVED_TO
+            | WatchMask::ATTRIB;
+        let wd = self.inotify.watches().add(dir, mask)?;
+        self.dirs.insert(wd, dir.to_path_buf());
+
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                self.watch(&entry.path())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wait until some files change, and return their paths.
+    ///
+    /// `backends` determine which files are sources. If events were lost because too many
+    /// happened at once, the directories are scanned again and `EVERYTHING` is returned.
+    pub fn wait(&mut self, backends: &backend::Backends) -> io::Result<Vec<path::PathBuf>> {
+        let mut buffer = [0; 4096];
+        loop {
+            let mut changed = Vec::new();
+            // The new directories, which must be watched as well.
+            let mut new_dirs = Vec::new();
+
+            // Block until something happens, then collect what else happens shortly after.
+            let mut events: Vec<_> = self.inotify.read_events_blocking(&mut buffer)?
+                .map(|event| event.to_owned())
+                .collect();
+            loop {
+                thread::sleep(DEBOUNCE);
+                match self.inotify.read_events(&mut buffer) {
+                    Ok(more) => events.extend(more.map(|event| event.to_owned())),
+                    Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(err) => return Err(err),
+                }
+            }
+
+            // The events do not tell what changed if some were lost, so start over.
+            if events.iter().any(|event| event.mask.contains(EventMask::Q_OVERFLOW)) {
+                // Directories may have been created in the meantime. Watching a directory again
+                // is harmless.
+                self.watch(&self.root.clone())?;
+                return Ok(vec![path::PathBuf::from(EVERYTHING)]);
+            }
+
+            for event in events {
+                // Forget about directories that were removed.
+                if event.mask.contains(EventMask::IGNORED) {
+                    self.dirs.remove(&event.wd);
+                    continue;
+                }
+
+                let (dir, name) = match (self.dirs.get(&event.wd), &event.name) {
+                    (Some(dir), Some(name)) => (dir, name),
+                    _ => continue,
+                };
+                let path = dir.join(name);
+                // Report paths relative to the source root, like the sources.
+                let relative = path.strip_prefix(&self.root).map(path::Path::to_path_buf).unwrap_or_else(|_| path.clone());
+                let is_dir = event.mask.contains(EventMask::ISDIR);
+                if !self.relevant(&relative, is_dir, backends) {
+                    continue;
+                }
+
+                if is_dir && event.mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                    new_dirs.push(path);
+                }
+                let path = relative;
+                if !changed.contains(&path) {
+                    changed.push(path);
+                }
+            }
+
+            for dir in new_dirs {
+                // The directory may be gone already.
+                match self.watch(&dir) {
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+                    result => result?,
+                }
+            }
+
+            if !changed.is_empty() {
+                return Ok(changed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    /// Create a source root named `name` with an output directory `deck`, ignoring `drafts` and
+    /// selecting the sources in `algebra` except Markdown files.
+    fn watcher(name: &str) -> (path::PathBuf, Watcher) {
+        let root = env::temp_dir().join(format!("mkmu-{}-{}", name, process::id()));
+        for dir in &["deck", "drafts", "algebra"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        fs::write(root.join(walk::IGNORE_FILE), "drafts/\n").unwrap();
+        let filter = walk::Filter::new(&root.join("deck"), &["algebra/**"], &["*.md"]).unwrap();
+        let watcher = Watcher::new(&root, filter).unwrap();
+        (root, watcher)
+    }
+
+    #[test]
+    fn relevant_changes() {
+        let (root, watcher) = watcher("relevant");
+        let backends = backend::Backends::default();
+        let relevant: Vec<_> = [
+            ("deck", true),
+            ("deck/group.pdf", false),
+            ("drafts", true),
+            ("drafts/ring.tex", false),
+            ("algebra/notes.md", false),
+            ("top.tex", false),
+            ("algebra/group.tex~", false),
+            ("algebra", true),
+            ("algebra/group.tex", false),
+            ("settings.mu", false),
+            ("template.tex", false),
+            ("templates/Math.tex", false),
+            ("algebra/_meta.mu", false),
+        ].iter()
+            .map(|&(path, is_dir)| watcher.relevant(path::Path::new(path), is_dir, &backends))
+            .collect();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(relevant, [false, false, false, false, false, false, false, true, true, true, true, true, true]);
+    }
+
+    #[test]
+    fn debounce() {
+        let (root, mut watcher) = watcher("debounce");
+        // Save several files in quick succession, as editors do.
+        let writer = {
+            let root = root.clone();
+            thread::spawn(move || {
+                for file in &["algebra/group.tex", "deck/group.pdf", "algebra/group.tex~", "settings.mu", "algebra/group.tex"] {
+                    fs::write(root.join(file), "").unwrap();
+                    thread::sleep(DEBOUNCE / 4);
+                }
+            })
+        };
+        let changed = watcher.wait(&backend::Backends::default());
+        writer.join().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(changed.unwrap(), [path::PathBuf::from("algebra/group.tex"), path::PathBuf::from("settings.mu")]);
+    }
+}
@@ -13,7 +13,7 @@ use std::{path, fs, fmt, env, process};
 use failure::Error;
 use termion::{color, style};
 use itertools::Itertools;
-use clap::{Arg, App};
+use clap::{Arg, App, SubCommand};
 
 /// The text that is printed when the `help` command is issued.
 const HELP: &'static str = r#"view, v  : View the current card
@@ -29,6 +29,7 @@ okay, o  : Review the card as okay
 good, g  : Review the card as good
 easy, e  : Review the card as easy
 skip, s  : Skip the card for this session
+answer, a: Type in an answer to the current card
 tnew, tn : Toggle whether new cards should be preferred"#;
 
 /// Formatter for durations.
@@ -36,34 +37,23 @@ struct DurationFormatter(chrono::Duration);
 
 impl fmt::Display for DurationFormatter {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        // Break the duration into units.
-        let mut duration = self.0;
-
-        if duration.is_zero() {
+        if self.0.is_zero() {
             // If the duration is empty, simply write an unitless 0.
             write!(f, "0")
         } else {
-            // Calculate the various units.
-            let years = duration.num_weeks() / 4 / 12;
-            // TODO: `-=` is not implemented upstream yet. Change the code when it is.
-            duration = duration - chrono::Duration::weeks(years * 4 * 12);
-            let months = duration.num_weeks() / 4;
-            duration = duration - chrono::Duration::weeks(months * 4);
-            let weeks = duration.num_weeks();
-            duration = duration - chrono::Duration::weeks(weeks);
-            let days = duration.num_days();
-            duration = duration - chrono::Duration::days(days);
-            let hours = duration.num_hours();
-            duration = duration - chrono::Duration::hours(hours);
-            let minutes = duration.num_minutes();
-
-            // Write non-zero parts.
-            if years != 0 { write!(f, "{}y ", years)?; }
-            if months != 0 { write!(f, "{}M ", months)?; }
-            if weeks != 0 { write!(f, "{}w ", weeks)?; }
-            if days != 0 { write!(f, "{}d ", days)?; }
-            if hours != 0 { write!(f, "{}h ", hours)?; }
-            if minutes != 0 { write!(f, "{}m ", minutes)?; }
+            // Break the duration into units, writing the non-zero parts down to minutes. The units
+            // are those of the deck, so months and years have their average calendar length.
+            let mut seconds = self.0.num_seconds();
+            let mut written = false;
+            for &(unit, length) in backend::DURATION_UNITS.iter() {
+                let count = seconds / length;
+                seconds -= count * length;
+                // Seconds are only written for durations shorter than a minute.
+                if count != 0 && (length >= 60 || !written) {
+                    write!(f, "{}{} ", count, unit)?;
+                    written = true;
+                }
+            }
 
             Ok(())
         }
@@ -76,10 +66,21 @@ pub struct State<W: Write, R> {
     scheduler: backend::Scheduler,
     /// Path to the schedule file.
     schedule_path: path::PathBuf,
+    /// The directory of the deck file.
+    deck_dir: path::PathBuf,
     /// Standard output.
     stdout: W,
     /// Standard input.
     stdin: io::Lines<R>,
+    /// The suggested score of the current card.
+    ///
+    /// This is set after a typed answer has been graded, and is used when the user enters an empty
+    /// command.
+    suggestion: Option<backend::Score>,
+    /// Should the current card be reviewed with the suggested score without confirmation?
+    ///
+    /// This is set when the command of a card asks for it.
+    automatic: bool,
 }
 
 impl<W: Write, R: io::BufRead> State<W, R> {
@@ -90,8 +91,12 @@ impl<W: Write, R: io::BufRead> State<W, R> {
         Ok(State {
             scheduler,
             schedule_path,
+            // `load` changes the directory to that of the deck.
+            deck_dir: env::current_dir()?,
             stdout: stdout,
             stdin: stdin.lines(),
+            suggestion: None,
+            automatic: false,
         })
     }
 
@@ -108,6 +113,7 @@ impl<W: Write, R: io::BufRead> State<W, R> {
     pub fn run(mut self) -> Result<(), Error> {
         // Show new card.
         self.show_card()?;
+        self.review_automatically()?;
         // Print the shell.
         self.print_shell()?;
 
@@ -115,6 +121,7 @@ impl<W: Write, R: io::BufRead> State<W, R> {
         while let Some(line) = self.stdin.next() {
             // Read command.
             if !self.command(&line?)? { break; };
+            self.review_automatically()?;
             // Print new shell.
             self.print_shell()?;
         }
@@ -122,12 +129,31 @@ impl<W: Write, R: io::BufRead> State<W, R> {
         Ok(())
     }
 
+    /// Review the current card if its command asked for it.
+    ///
+    /// This is repeated as long as the new cards ask for it as well.
+    fn review_automatically(&mut self) -> Result<(), Error> {
+        while self.automatic {
+            self.automatic = false;
+            if let Some(score) = self.suggestion {
+                writeln!(self.stdout, "reviewing as {}.", score)?;
+                self.review(score)?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Print the shell, that is, the text before the command input.
     pub fn print_shell(&mut self) -> Result<(), Error> {
-        write!(self.stdout, "D:{} N:{}{} {}>>{} ",
+        write!(self.stdout, "D:{} N:{}{}{} {}>>{} ",
             self.scheduler.due_cards(),
             self.scheduler.new_cards(),
             if self.scheduler.prefer_new { "-" } else { "" },
+            match self.suggestion {
+                Some(score) => format!(" [{}]", score),
+                None => String::new(),
+            },
             color::Fg(color::Red),
             color::Fg(color::Reset),
         )?;
@@ -158,6 +184,8 @@ impl<W: Write, R: io::BufRead> State<W, R> {
             "easy" | "e" => self.review(backend::Score::Easy)?,
             // Skip the card.
             "skip" | "s" => self.skip()?,
+            // Type in an answer.
+            "answer" | "a" => self.answer()?,
             // Print card information.
             "info" | "i" => self.print_info()?,
             // Print metadata of the card.
@@ -172,8 +200,10 @@ impl<W: Write, R: io::BufRead> State<W, R> {
             "help" | "he" => self.help()?,
             // Toggle preference for new cards.
             "tnew" | "tn" => self.scheduler.prefer_new ^= true,
-            // Skip.
-            "" => (),
+            // Confirm the suggested score.
+            "" => if let Some(score) = self.suggestion {
+                self.review(score)?;
+            },
             // Unknown command.
             _ => writeln!(self.stdout, "Unknown command '{}'.", command)?,
         }
@@ -201,6 +231,9 @@ impl<W: Write, R: io::BufRead> State<W, R> {
     fn review(&mut self, score: backend::Score) -> Result<(), Error> {
         // Review the card.
         self.scheduler.review(score);
+        // Forget the suggestion for the old card.
+        self.suggestion = None;
+        self.automatic = false;
         // Write the schedule to the file system.
         self.write()?;
         // Show the new card.
@@ -212,6 +245,9 @@ impl<W: Write, R: io::BufRead> State<W, R> {
     fn skip(&mut self) -> Result<(), Error> {
         // Skip the card.
         self.scheduler.skip();
+        // Forget the suggestion for the old card.
+        self.suggestion = None;
+        self.automatic = false;
         // Write the schedule to the file system.
         self.write()?;
         // Write a message to the user.
@@ -230,7 +266,65 @@ impl<W: Write, R: io::BufRead> State<W, R> {
         // View the card.
         self.view_card()?;
         // Print the new intervals.
-        self.print_intervals()
+        self.print_intervals()?;
+        // Ask for an answer if the card expects one.
+        if !self.scheduler.current_card().answers.is_empty() {
+            self.answer()?;
+        }
+
+        Ok(())
+    }
+
+    /// Prompt the user for an answer to the current card and grade it.
+    ///
+    /// The score of the grade becomes the suggested score, which the user can confirm or override.
+    fn answer(&mut self) -> Result<(), Error> {
+        // Cards without answers cannot be graded.
+        if self.scheduler.current_card().answers.is_empty() {
+            writeln!(self.stdout, "card has no answer.")?;
+            return Ok(());
+        }
+
+        // Print the prompt.
+        self.print_header(format_args!("answer"))?;
+        write!(self.stdout, "{}?{} ", color::Fg(color::Blue), color::Fg(color::Reset))?;
+        self.stdout.flush()?;
+        // Read the answer.
+        let given = match self.stdin.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+
+        // Grade the answer.
+        let grade = self.scheduler.current_card().grade(&given);
+        // Show the expected answer and the difference, if it did not match.
+        if let Some(ref expected) = grade.expected {
+            writeln!(self.stdout, "expected:  {}", expected)?;
+            if !grade.diff.is_empty() {
+                write!(self.stdout, "diff:      ")?;
+                for chunk in &grade.diff {
+                    match chunk {
+                        backend::DiffChunk::Same(s) => write!(self.stdout, "{}", s)?,
+                        backend::DiffChunk::Missing(s) => write!(self.stdout, "{}{}{}",
+                            color::Fg(color::Green),
+                            s,
+                            color::Fg(color::Reset),
+                        )?,
+                        backend::DiffChunk::Extra(s) => write!(self.stdout, "{}{}{}{}",
+                            color::Fg(color::Red),
+                            style::CrossedOut,
+                            s,
+                            style::Reset,
+                        )?,
+                    }
+                }
+                writeln!(self.stdout)?;
+            }
+        }
+        writeln!(self.stdout, "suggested: {} (press enter to confirm, or give another score)", grade.score)?;
+        self.suggestion = Some(grade.score);
+
+        Ok(())
     }
 
     /// Print card information.
@@ -249,6 +343,27 @@ impl<W: Write, R: io::BufRead> State<W, R> {
         )?;
         writeln!(self.stdout, "tags:      {}", self.scheduler.current_card().tags.iter().format(", "))?;
         writeln!(self.stdout, "priority:  {}", self.scheduler.current_card().priority)?;
+        // Print which settings apply, and which keys the card overrides.
+        let card = self.scheduler.current_card();
+        let group = match self.scheduler.deck().settings_group(card) {
+            "" => "default",
+            group => group,
+        };
+        if card.overrides.is_empty() {
+            writeln!(self.stdout, "settings:  {}", group)?;
+        } else {
+            writeln!(self.stdout, "settings:  {} (overriding {})", group, card.overrides.iter().map(|(key, _)| key).format(", "))?;
+        }
+        if let Some(position) = card.position {
+            writeln!(self.stdout, "position:  {}", position)?;
+        }
+        if !card.requires.is_empty() {
+            writeln!(self.stdout, "requires:  {}", card.requires.iter().format(", "))?;
+        }
+        // Print the user-defined fields.
+        for (key, value) in &card.fields {
+            writeln!(self.stdout, "{:<11}{}", format!("{}:", key), value)?;
+        }
 
         Ok(())
     }
@@ -315,8 +430,8 @@ impl<W: Write, R: io::BufRead> State<W, R> {
             sched.statistics().retention_rate() * 100.0,
             sched.statistics().familiarity(),
         )?;
-        // Print retention rates for each tag.
-        for (tag, stat) in sched.tag_statistics() {
+        // Print retention rates for each tag, sorted such that tags follow their parents.
+        for (tag, stat) in sched.tag_statistics().iter().sorted_by(|a, b| a.0.cmp(b.0)) {
             writeln!(self.stdout, "{}: {:.1}%, {:.2}",
                 tag,
                 stat.retention_rate() * 100.0,
@@ -349,7 +464,27 @@ impl<W: Write, R: io::BufRead> State<W, R> {
                         .wait()?;
                 },
                 backend::View::Command(cmd) => {
-                    cmd.execute()?.wait()?;
+                    // Run the command.
+                    let outcome = cmd.execute(card, self.scheduler.current_metacard(), &self.deck_dir)?;
+                    // Use the reported score, if any.
+                    if let Some(score) = outcome.score {
+                        writeln!(self.stdout, "suggested: {} (reported by command)", score)?;
+                        self.suggestion = Some(score);
+                        self.automatic = outcome.automatic;
+                    } else if outcome.timed_out {
+                        writeln!(self.stdout, "{}(command timed out){}", color::Fg(color::Red), color::Fg(color::Reset))?;
+                    } else if !outcome.status.success() {
+                        writeln!(self.stdout, "{}(command failed: {}){}",
+                            color::Fg(color::Red),
+                            outcome.status,
+                            color::Fg(color::Reset),
+                        )?;
+                        // Repeat the last line of the error output, as it is likely the most
+                        // relevant one.
+                        if let Some(line) = outcome.stderr.lines().last() {
+                            writeln!(self.stdout, "last error: {}", line)?;
+                        }
+                    }
                 },
             }
         }
@@ -358,13 +493,26 @@ impl<W: Write, R: io::BufRead> State<W, R> {
     }
 }
 
+/// Load the deck file at `deck_path`, along with the files it includes.
+fn load_deck(deck_path: &path::Path) -> Result<backend::Deck, Error> {
+    let (deck, diagnostics) = backend::Deck::load_with_diagnostics(deck_path)?;
+    // Show where the first error is, if any.
+    if let Some(err) = diagnostics.errors.first() {
+        let file = diagnostics.file(err.span());
+        return Err(failure::err_msg(err.render(&file.path.display().to_string(), &file.src)));
+    }
+
+    Ok(deck)
+}
+
 /// Load a deck and schedule.
 ///
 /// This returns a scheduler and a path to the schedule (a canonicalized version of
 /// `schedule_path`).
 fn load(deck_path: &path::Path, mut schedule_path: path::PathBuf) -> Result<(backend::Scheduler, path::PathBuf), Error> {
+    // Load the deck (along with the files it includes) before changing directory.
+    let deck = load_deck(deck_path)?;
     // Open files.
-    let mut deck_file = fs::File::open(deck_path)?;
     let mut schedule_file = fs::OpenOptions::new()
         .read(true)
         .write(true)
@@ -376,12 +524,8 @@ fn load(deck_path: &path::Path, mut schedule_path: path::PathBuf) -> Result<(bac
     let deck_path = fs::canonicalize(deck_path)?;
     env::set_current_dir(deck_path.parent().unwrap())?;
     // Read files.
-    let mut deck_buffer = String::new();
-    deck_file.read_to_string(&mut deck_buffer)?;
     let mut schedule_buffer = String::new();
     schedule_file.read_to_string(&mut schedule_buffer)?;
-    // Parse.
-    let deck = backend::Deck::parse(&deck_buffer)?;
     let schedule = if schedule_buffer.is_empty() {
         // When the file is empty (e.g. first time the schedule is loaded), use the default,
         // empty schedule.
@@ -393,6 +537,84 @@ fn load(deck_path: &path::Path, mut schedule_path: path::PathBuf) -> Result<(bac
     Ok((backend::Scheduler::new(deck, schedule), schedule_path))
 }
 
+/// Check the deck file at `deck_path`, printing all errors and warnings to `stdout`.
+///
+/// This returns whether the deck is valid.
+fn check<W: Write>(stdout: &mut W, deck_path: &path::Path) -> Result<bool, Error> {
+    let (_, diagnostics) = backend::Deck::load_with_diagnostics(deck_path)?;
+    // Errors and warnings are shown in the file they occur in, which may be an included file.
+    let file = |span| {
+        let file = diagnostics.file(span);
+        (file.path.display().to_string(), &file.src)
+    };
+
+    for err in &diagnostics.errors {
+        let (name, src) = file(err.span());
+        writeln!(stdout, "{}error{}: {}\n", color::Fg(color::Red), color::Fg(color::Reset), err.render(&name, src))?;
+    }
+    for warning in &diagnostics.warnings {
+        let (name, src) = file(warning.span());
+        writeln!(stdout, "{}warning{}: {}\n", color::Fg(color::Yellow), color::Fg(color::Reset), warning.render(&name, src))?;
+    }
+    writeln!(stdout, "{} error(s), {} warning(s)", diagnostics.errors.len(), diagnostics.warnings.len())?;
+
+    Ok(diagnostics.errors.is_empty())
+}
+
+/// Normalize the deck file at `deck_path`, writing the outcome to `stdout`.
+///
+/// With `check_only`, the file is left as it is. This returns whether the file was formatted
+/// already.
+fn format<W: Write>(stdout: &mut W, deck_path: &path::Path, check_only: bool) -> Result<bool, Error> {
+    let (deck, diagnostics) = backend::Deck::load_with_diagnostics(deck_path)?;
+    // Refuse to format decks that would not be written back as they are.
+    if !diagnostics.errors.is_empty() {
+        return Err(failure::err_msg(format!("{} has errors; see `mu check`", deck_path.display())));
+    }
+    if diagnostics.files.len() > 1 {
+        return Err(failure::err_msg(format!("{} includes other files, which cannot be formatted", deck_path.display())));
+    }
+    if diagnostics.files[0].has_comments() {
+        return Err(failure::err_msg(format!("{} has comments, which would be lost by formatting", deck_path.display())));
+    }
+
+    let formatted = deck.to_mu_string();
+    if formatted == diagnostics.files[0].src {
+        return Ok(true);
+    }
+    if check_only {
+        writeln!(stdout, "{} is not formatted", deck_path.display())?;
+    } else {
+        // Replace the file atomically, such that it is never lost.
+        let tmp_path = deck_path.with_extension("mu.tmp");
+        fs::write(&tmp_path, formatted)?;
+        fs::rename(tmp_path, deck_path)?;
+        writeln!(stdout, "Formatted {}", deck_path.display())?;
+    }
+
+    Ok(false)
+}
+
+/// Print the IDs of the cards in the deck file at `deck_path` with any of the tags `tags` (or
+/// their descendants) and all of the fields `fields` to `stdout`.
+///
+/// A field filter `(key, value)` matches cards whose field `key` contains `value`, ignoring case.
+/// If `tags` is empty, cards are not filtered by tag.
+fn list<W: Write>(stdout: &mut W, deck_path: &path::Path, tags: &[&str], fields: &[(&str, &str)]) -> Result<(), Error> {
+    let deck = load_deck(deck_path)?;
+    for (id, card) in deck.cards.iter().sorted_by(|a, b| a.0.cmp(b.0)) {
+        let tagged = tags.is_empty() || card.tags.iter().any(|tag| tags.iter().any(|filter| backend::tag_matches(tag, filter)));
+        let has_fields = fields.iter().all(|&(key, value)| card.fields
+            .get(key)
+            .is_some_and(|field| field.to_lowercase().contains(&value.to_lowercase())));
+        if tagged && has_fields {
+            writeln!(stdout, "{}", id)?;
+        }
+    }
+
+    Ok(())
+}
+
 // TODO: Better error messages
 /// Start mu.
 fn main_err() -> Result<(), Error> {
@@ -415,12 +637,80 @@ fn main_err() -> Result<(), Error> {
              .long("queued")
              // TODO: Use a more automated way of specifying the default value.
              .help("Prints number of cards to be reviewed and quits"))
+        .subcommand(SubCommand::with_name("check")
+             .about("Reports all errors and likely mistakes in a deck")
+             .arg(Arg::with_name("DECK")
+                  .help("Sets the '.mu' deck file to check")
+                  .default_value("deck/deck.mu")))
+        .subcommand(SubCommand::with_name("fmt")
+             .about("Rewrites a deck in canonical form (unless it has comments or includes)")
+             .arg(Arg::with_name("DECK")
+                  .help("Sets the '.mu' deck file to format")
+                  .default_value("deck/deck.mu"))
+             .arg(Arg::with_name("check")
+                  .long("check")
+                  .help("Exits with an error if the deck is not formatted, rather than formatting it")))
+        .subcommand(SubCommand::with_name("list")
+             .about("Prints the IDs of the cards in a deck")
+             .arg(Arg::with_name("DECK")
+                  .help("Sets the '.mu' deck file to list")
+                  .default_value("deck/deck.mu"))
+             .arg(Arg::with_name("tag")
+                  .short("t")
+                  .long("tag")
+                  .value_name("TAG")
+                  .help("Only prints cards with the tag or its subtags (e.g. Math matches Math::Algebra)")
+                  .takes_value(true)
+                  .multiple(true)
+                  .number_of_values(1))
+             .arg(Arg::with_name("field")
+                  .short("f")
+                  .long("field")
+                  .value_name("KEY=VALUE")
+                  .help("Only prints cards whose field KEY contains VALUE (ignoring case)")
+                  .takes_value(true)
+                  .multiple(true)
+                  .number_of_values(1)))
         .get_matches();
 
     // Lock stdout.
     let stdout = io::stdout();
     let mut stdout = stdout.lock();
 
+    if let Some(matches) = matches.subcommand_matches("check") {
+        let valid = check(&mut stdout, path::Path::new(matches.value_of("DECK").unwrap()))?;
+        stdout.flush()?;
+        // Exit with an error if the deck is invalid, such that it can be used in scripts.
+        if !valid {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(matches) = matches.subcommand_matches("fmt") {
+        let check_only = matches.is_present("check");
+        let formatted = format(&mut stdout, path::Path::new(matches.value_of("DECK").unwrap()), check_only)?;
+        stdout.flush()?;
+        if check_only && !formatted {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("list") {
+        let tags: Vec<_> = matches.values_of("tag").map(Iterator::collect).unwrap_or_default();
+        let field_filters: Vec<_> = matches.values_of("field").map(Iterator::collect).unwrap_or_default();
+        let mut fields = Vec::new();
+        for field in field_filters {
+            // Split the filter at the first `=`.
+            let mut parts = field.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => fields.push((key.trim(), value.trim())),
+                _ => return Err(failure::err_msg(format!("invalid field filter `{}` (expected KEY=VALUE)", field))),
+            }
+        }
+        return list(&mut stdout, path::Path::new(matches.value_of("DECK").unwrap()), &tags, &fields);
+    }
+
     // The deck of cards.
     let deck = path::PathBuf::from(matches.value_of("DECK").unwrap());
 
@@ -465,3 +755,30 @@ fn main() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(DurationFormatter(chrono::Duration::zero()).to_string(), "0");
+        assert_eq!(DurationFormatter(chrono::Duration::seconds(30)).to_string(), "30s ");
+        assert_eq!(DurationFormatter(chrono::Duration::seconds(90)).to_string(), "1m ");
+        assert_eq!(DurationFormatter(chrono::Duration::hours(36)).to_string(), "1d 12h ");
+    }
+
+    #[test]
+    fn format_keeps_comments() {
+        let path = env::temp_dir().join(format!("mu-format-{}.mu", process::id()));
+        let src = "[card b]\n# Ask about this in the exam.\ntags: Algebra\n\n[card a]\ntags: Algebra\n";
+        fs::write(&path, src).unwrap();
+
+        // The deck is not formatted, but rewriting it would drop the comment.
+        let result = format(&mut Vec::new(), &path, false);
+        let after = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+        assert_eq!(after, src);
+    }
+}